@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use graphql_client::{GraphQLQuery, Response};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::airstack::fetch_query;
+use crate::providers::CastType;
+use crate::routes::config::Config;
+use crate::upstream::{
+    calls::record_call,
+    deadline::{current_deadline, with_deadline},
+    UpstreamError,
+};
+
+// A cast looked up by hash, Airstack lists top-level casts and replies separately.
+// Nodes are kept as raw JSON so each caller can pick the fields it needs.
+#[derive(Debug, Clone, Default)]
+pub struct BatchedCast {
+    pub cast: Option<Value>,
+    pub reply: Option<Value>,
+}
+
+impl BatchedCast {
+    // The node of the requested type, either one when the type isn't known. A node
+    // that doesn't decode as `T` is an invalid response, not a missing cast.
+    pub fn select<T: DeserializeOwned>(
+        self,
+        cast_type: Option<&CastType>,
+    ) -> Result<Option<T>, UpstreamError> {
+        let node = match cast_type {
+            Some(CastType::Cast) => self.cast,
+            Some(CastType::Reply) => self.reply,
+            None => self.cast.or(self.reply),
+        };
+        node.map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| UpstreamError::InvalidResponse {
+                upstream: "Airstack".to_string(),
+                error: e.to_string(),
+            })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct CastsByHashesData {
+    farcaster_casts: Option<CastNodes>,
+    farcaster_replies: Option<CastNodes>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct CastNodes {
+    #[serde(default)]
+    cast: Vec<Value>,
+    #[serde(default)]
+    reply: Vec<Value>,
+}
+
+type Waiter = oneshot::Sender<Result<Option<BatchedCast>, UpstreamError>>;
+
+#[derive(Default)]
+struct Pending {
+    // bumped on every flush so a stale timer doesn't flush the next batch early
+    generation: u64,
+    waiters: HashMap<String, Vec<Waiter>>,
+    // latest deadline of the waiting requests, none once one of them has none
+    deadline: Option<Instant>,
+}
+
+impl Pending {
+    // A short deadline only cuts its own request short, each caller stops waiting at
+    // its own deadline while the batch runs on for the others
+    fn add(&mut self, hash: String, tx: Waiter, deadline: Option<Instant>) {
+        self.deadline = match (self.waiters.is_empty(), self.deadline, deadline) {
+            (true, _, deadline) => deadline,
+            (false, Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+        self.waiters.entry(hash).or_default().push(tx);
+    }
+
+    fn take(&mut self) -> (HashMap<String, Vec<Waiter>>, Option<Instant>) {
+        self.generation += 1;
+        (std::mem::take(&mut self.waiters), self.deadline.take())
+    }
+}
+
+// Collects cast lookups by hash made within a short window and sends them to
+// Airstack as a single `_in` query, fanning the results back out to the callers.
+pub struct CastBatcher {
+    config: Arc<Config>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl CastBatcher {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            pending: Arc::new(Mutex::new(Pending::default())),
+        }
+    }
+
+    pub async fn load(&self, hash: &str) -> Result<Option<BatchedCast>, UpstreamError> {
+        let hash = hash.to_lowercase();
+        let (tx, rx) = oneshot::channel();
+        let deadline = current_deadline();
+
+        let full_batch = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.waiters.is_empty() {
+                self.schedule_flush(pending.generation);
+            }
+            pending.add(hash.clone(), tx, deadline);
+            if pending.waiters.len() >= self.config.airstack_batch_size {
+                Some(pending.take())
+            } else {
+                None
+            }
+        };
+        if let Some((waiters, deadline)) = full_batch {
+            tokio::spawn(run_batch(self.config.clone(), waiters, deadline));
+        }
+
+        match rx.await {
+            Ok(result) => {
+                // the batch ran in its own task, count it for this caller too
+                record_call();
+                result
+            }
+            // the batch task went away, look the cast up on its own
+            Err(_) => fetch_casts(&self.config, vec![hash.clone()], None)
+                .await
+                .map(|mut casts| casts.remove(&hash)),
+        }
+    }
+
+    fn schedule_flush(&self, generation: u64) {
+        let config = self.config.clone();
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(config.airstack_batch_window_ms)).await;
+            let (waiters, deadline) = {
+                let mut pending = pending.lock().unwrap();
+                if pending.generation != generation {
+                    return;
+                }
+                pending.take()
+            };
+            run_batch(config, waiters, deadline).await;
+        });
+    }
+}
+
+async fn run_batch(
+    config: Arc<Config>,
+    waiters: HashMap<String, Vec<Waiter>>,
+    deadline: Option<Instant>,
+) {
+    // sorted so that the same casts always make the same query
+    let mut hashes: Vec<String> = waiters.keys().cloned().collect();
+    hashes.sort();
+    match fetch_casts(&config, hashes, deadline).await {
+        Ok(mut casts) => {
+            for (hash, senders) in waiters {
+                let cast = casts.remove(&hash);
+                for sender in senders {
+                    let _ = sender.send(Ok(cast.clone()));
+                }
+            }
+        }
+        Err(e) => {
+            let e = Arc::new(e);
+            for sender in waiters.into_values().flatten() {
+                let _ = sender.send(Err(UpstreamError::Shared(e.clone())));
+            }
+        }
+    }
+}
+
+// Batches run in their own task, where the request deadline has to be set again. The
+// limit is raised to the batch so that Airstack's default page doesn't cut it short,
+// batches are capped at `AIRSTACK_PAGE_SIZE` for that.
+async fn fetch_casts(
+    config: &Config,
+    hashes: Vec<String>,
+    deadline: Option<Instant>,
+) -> Result<HashMap<String, BatchedCast>, UpstreamError> {
+    let request_body = CastsByHashesQuery::build_query(casts_by_hashes_query::Variables {
+        limit: hashes.len() as i64,
+        hashes,
+    });
+    let query = fetch_query::<_, Response<CastsByHashesData>>(config, &request_body);
+    let res = match deadline {
+        Some(deadline) => with_deadline(deadline, query).await?,
+        None => query.await?,
+    };
+
+    let mut casts: HashMap<String, BatchedCast> = HashMap::new();
+    if let Some(data) = res.data {
+        let key = |node: &Value| node["hash"].as_str().map(|h| h.to_lowercase());
+        for node in data.farcaster_casts.map(|c| c.cast).unwrap_or_default() {
+            if let Some(hash) = key(&node) {
+                casts.entry(hash).or_default().cast = Some(node);
+            }
+        }
+        for node in data.farcaster_replies.map(|r| r.reply).unwrap_or_default() {
+            if let Some(hash) = key(&node) {
+                casts.entry(hash).or_default().reply = Some(node);
+            }
+        }
+    }
+    Ok(casts)
+}
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/cast_batch_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct CastsByHashesQuery;
+
+type Map = Value;
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Json, Router};
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Node {
+        hash: String,
+    }
+
+    fn batched() -> BatchedCast {
+        BatchedCast {
+            cast: Some(json!({"hash": "0xcast"})),
+            reply: Some(json!({"hash": "0xreply"})),
+        }
+    }
+
+    fn hash(node: Option<Node>) -> Option<String> {
+        node.map(|n| n.hash)
+    }
+
+    #[test]
+    fn selects_the_node_of_the_requested_type() {
+        let cast = batched().select::<Node>(Some(&CastType::Cast)).unwrap();
+        assert_eq!(hash(cast).as_deref(), Some("0xcast"));
+        let reply = batched().select::<Node>(Some(&CastType::Reply)).unwrap();
+        assert_eq!(hash(reply).as_deref(), Some("0xreply"));
+        let either = batched().select::<Node>(None).unwrap();
+        assert_eq!(hash(either).as_deref(), Some("0xcast"));
+
+        let only_reply = BatchedCast {
+            cast: None,
+            reply: Some(json!({"hash": "0xreply"})),
+        };
+        assert_eq!(
+            hash(only_reply.select::<Node>(None).unwrap()).as_deref(),
+            Some("0xreply")
+        );
+        assert!(BatchedCast::default()
+            .select::<Node>(None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_run_until_the_latest_deadline() {
+        let now = Instant::now();
+        let soon = now + Duration::from_millis(10);
+        let later = now + Duration::from_secs(10);
+        let add = |pending: &mut Pending, hash: &str, deadline| {
+            pending.add(hash.to_string(), oneshot::channel().0, deadline)
+        };
+
+        let mut pending = Pending::default();
+        add(&mut pending, "0x1", Some(later));
+        add(&mut pending, "0x2", Some(soon));
+        assert_eq!(pending.take().1, Some(later));
+
+        add(&mut pending, "0x1", Some(soon));
+        add(&mut pending, "0x2", None);
+        add(&mut pending, "0x3", Some(later));
+        assert_eq!(pending.take().1, None);
+
+        add(&mut pending, "0x1", Some(soon));
+        assert_eq!(pending.take().1, Some(soon));
+    }
+
+    #[test]
+    fn reports_undecodable_nodes_as_invalid_responses() {
+        let batched = BatchedCast {
+            cast: Some(json!({"hash": 1})),
+            reply: None,
+        };
+        let err = batched.select::<Node>(None).unwrap_err();
+        assert!(matches!(err, UpstreamError::InvalidResponse { .. }));
+        assert_eq!(err.status_code(), axum::http::StatusCode::BAD_GATEWAY);
+    }
+
+    // An Airstack stub that, like Airstack, returns at most `limit` casts and 50 by default
+    async fn stub_airstack() -> String {
+        let app = Router::new().route(
+            "/",
+            post(|Json(body): Json<Value>| async move {
+                let variables = &body["variables"];
+                let limit = variables["limit"].as_u64().unwrap_or(50) as usize;
+                let casts: Vec<Value> = variables["hashes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .take(limit)
+                    .map(|hash| json!({"hash": hash}))
+                    .collect();
+                Json(json!({"data": {
+                    "FarcasterCasts": {"Cast": casts},
+                    "FarcasterReplies": {"Reply": []},
+                }}))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn finds_every_cast_of_a_batch_larger_than_the_default_page() {
+        std::env::set_var("API_KEY", "test");
+        std::env::set_var("AIRSTACK_API_KEY", "batch-test");
+        std::env::set_var("AIRSTACK_API_URL", stub_airstack().await);
+        let config = Config::from_env();
+
+        let hashes: Vec<String> = (0..120).map(|i| format!("0x{:04x}", i)).collect();
+        let casts = fetch_casts(&config, hashes.clone(), None).await.unwrap();
+        assert_eq!(casts.len(), hashes.len());
+        assert!(hashes.iter().all(|hash| casts[hash].cast.is_some()));
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::{Mutex, OnceLock};
+
+// most hedges that can be saved up during quiet periods
+const MAX_TOKENS: f64 = 10.0;
+
+// Caps hedged requests to a share of all requests. Every request earns a fraction of
+// a token, every hedge spends a whole one.
+pub struct HedgeBudget {
+    tokens: Mutex<f64>,
+    per_request: f64,
+}
+
+impl HedgeBudget {
+    fn new(percent: u64) -> Self {
+        Self {
+            tokens: Mutex::new(0.0),
+            per_request: percent as f64 / 100.0,
+        }
+    }
+
+    pub fn record_request(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.per_request).min(MAX_TOKENS);
+    }
+
+    pub fn try_hedge(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+static HEDGE_BUDGET: OnceLock<HedgeBudget> = OnceLock::new();
+
+pub fn hedge_budget(percent: u64) -> &'static HedgeBudget {
+    HEDGE_BUDGET.get_or_init(|| HedgeBudget::new(percent))
+}
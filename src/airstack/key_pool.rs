@@ -0,0 +1,177 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::routes::config::{AirstackApiKey, Config};
+use crate::upstream::rate_limit::key_suffix;
+
+struct PooledKey {
+    key: String,
+    weight: i64,
+    // running weight of the smooth weighted round-robin
+    current: i64,
+    parked_until: Option<Instant>,
+    requests: u64,
+    rate_limited: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyUsage {
+    pub key: String,
+    pub weight: i64,
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub parked_for_secs: u64,
+}
+
+// Airstack API keys requests are spread over in proportion to their weight. Keys
+// that hit a rate limit or quota are parked and skipped until the park expires.
+pub struct KeyPool {
+    keys: Mutex<Vec<PooledKey>>,
+    park_for: Duration,
+}
+
+impl KeyPool {
+    pub fn new(keys: &[AirstackApiKey], park_for: Duration) -> Self {
+        Self {
+            keys: Mutex::new(
+                keys.iter()
+                    .map(|k| PooledKey {
+                        key: k.key.clone(),
+                        weight: k.weight.max(1) as i64,
+                        current: 0,
+                        parked_until: None,
+                        requests: 0,
+                        rate_limited: 0,
+                    })
+                    .collect(),
+            ),
+            park_for,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    // Picks the next key that isn't parked, `None` when all of them are
+    pub fn next(&self) -> Option<String> {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        let mut available: Vec<&mut PooledKey> = keys
+            .iter_mut()
+            .filter(|k| k.parked_until.is_none_or(|until| until <= now))
+            .collect();
+        let total: i64 = available.iter().map(|k| k.weight).sum();
+        for k in available.iter_mut() {
+            k.current += k.weight;
+        }
+        let picked = available.into_iter().max_by_key(|k| k.current)?;
+        picked.current -= total;
+        picked.parked_until = None;
+        picked.requests += 1;
+        Some(picked.key.clone())
+    }
+
+    // Parks the key for `retry_after` when the upstream said so, the configured
+    // duration otherwise
+    pub fn park(&self, key: &str, retry_after: Option<Duration>) {
+        let park_for = retry_after.unwrap_or(self.park_for);
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(k) = keys.iter_mut().find(|k| k.key == key) {
+            k.parked_until = Some(Instant::now() + park_for);
+            k.rate_limited += 1;
+            eprintln!(
+                "Airstack key ...{} parked for {}s",
+                key_suffix(key),
+                park_for.as_secs()
+            );
+        }
+    }
+
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        let now = Instant::now();
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|k| KeyUsage {
+                key: format!("...{}", key_suffix(&k.key)),
+                weight: k.weight,
+                requests: k.requests,
+                rate_limited: k.rate_limited,
+                parked_for_secs: k
+                    .parked_until
+                    .map(|until| until.saturating_duration_since(now).as_secs())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+static KEY_POOL: OnceLock<KeyPool> = OnceLock::new();
+
+// Returns the process wide pool of the configured Airstack keys
+pub fn key_pool(config: &Config) -> &'static KeyPool {
+    KEY_POOL.get_or_init(|| {
+        KeyPool::new(
+            &config.airstack_api_keys,
+            Duration::from_secs(config.airstack_key_park_secs),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[(&str, u32)]) -> KeyPool {
+        let keys: Vec<AirstackApiKey> = weights
+            .iter()
+            .map(|(key, weight)| AirstackApiKey {
+                key: key.to_string(),
+                weight: *weight,
+            })
+            .collect();
+        KeyPool::new(&keys, Duration::from_secs(60))
+    }
+
+    fn picks(pool: &KeyPool, n: usize) -> String {
+        (0..n)
+            .map(|_| pool.next().unwrap_or("-".to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn spreads_requests_smoothly_by_weight() {
+        let pool = pool(&[("a", 5), ("b", 1), ("c", 1)]);
+        let picked = picks(&pool, 14);
+        assert_eq!(picked.matches('a').count(), 10);
+        assert_eq!(picked.matches('b').count(), 2);
+        assert_eq!(picked.matches('c').count(), 2);
+        // the heavy key is interleaved with the others instead of sent in one burst
+        assert_eq!(&picked[..7], "aacabaa");
+    }
+
+    #[test]
+    fn skips_parked_keys_until_the_park_expires() {
+        let pool = pool(&[("a", 1), ("b", 1)]);
+        pool.park("a", None);
+        assert_eq!(picks(&pool, 3), "bbb");
+
+        pool.park("b", Some(Duration::ZERO));
+        assert_eq!(picks(&pool, 2), "bb");
+
+        pool.park("b", None);
+        assert_eq!(pool.next(), None);
+    }
+
+    #[test]
+    fn zero_weights_count_as_one() {
+        let pool = pool(&[("a", 0), ("b", 1)]);
+        let picked = picks(&pool, 4);
+        assert_eq!(picked.matches('a').count(), 2);
+    }
+}
@@ -1,11 +1,117 @@
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt::Debug;
 use std::time::Duration;
 
+use reqwest::StatusCode;
+
+use crate::routes::config::Config;
+use crate::upstream::{
+    fixtures::send,
+    rate_limit::{limiter, RateLimitError},
+    UpstreamError,
+};
+
+pub mod batch;
+mod hedge;
+pub mod key_pool;
+
+// most results Airstack returns for a list, which holds 50 when no limit is given
+pub const AIRSTACK_PAGE_SIZE: usize = 200;
+
+use hedge::hedge_budget;
+use key_pool::key_pool;
+
+// Sends the query, hedging it with a second identical request when the first one is
+// slower than the configured delay. Only queries are sent, so duplicates are harmless.
 pub async fn fetch_query<IT: ?Sized + Serialize, OT: DeserializeOwned + Debug>(
-    api_key: String,
+    config: &Config,
+    request_body: &IT,
+) -> Result<OT, UpstreamError> {
+    if config.airstack_hedge_delay_ms == 0 {
+        return fetch_with_rotation(config, request_body).await;
+    }
+    let budget = hedge_budget(config.airstack_hedge_budget_percent);
+    budget.record_request();
+
+    let primary = fetch_with_rotation(config, request_body);
+    tokio::pin!(primary);
+    tokio::select! {
+        res = &mut primary => return res,
+        _ = tokio::time::sleep(Duration::from_millis(config.airstack_hedge_delay_ms)) => {}
+    }
+    if !budget.try_hedge() {
+        return primary.await;
+    }
+
+    // the first successful response wins, dropping the other request cancels it
+    let hedged = fetch_with_rotation(config, request_body);
+    tokio::pin!(hedged);
+    tokio::select! {
+        res = &mut primary => match res {
+            Ok(value) => Ok(value),
+            Err(_) => hedged.await,
+        },
+        res = &mut hedged => match res {
+            Ok(value) => Ok(value),
+            Err(_) => primary.await,
+        },
+    }
+}
+
+// Sends the query with the next key of the pool, moving on to another key when
+// Airstack rate limits the current one
+async fn fetch_with_rotation<IT: ?Sized + Serialize, OT: DeserializeOwned + Debug>(
+    config: &Config,
     request_body: &IT,
-) -> Result<OT, reqwest::Error> {
+) -> Result<OT, UpstreamError> {
+    let pool = key_pool(config);
+    let mut last_error = None;
+    for _ in 0..pool.size() {
+        let api_key = match pool.next() {
+            Some(api_key) => api_key,
+            None => break,
+        };
+        match fetch_query_with_key(config, &api_key, request_body).await {
+            Err(KeyError::RateLimited(status, retry_after)) => {
+                pool.park(&api_key, retry_after);
+                last_error = Some(UpstreamError::Status {
+                    upstream: "Airstack".to_string(),
+                    status,
+                });
+            }
+            Err(KeyError::Upstream(e)) => return Err(e),
+            Ok(value) => return Ok(value),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        RateLimitError::KeysExhausted {
+            upstream: "Airstack".to_string(),
+        }
+        .into()
+    }))
+}
+
+enum KeyError {
+    // the key hit a rate limit or its quota, with the Retry-After if sent
+    RateLimited(StatusCode, Option<Duration>),
+    Upstream(UpstreamError),
+}
+
+impl<E: Into<UpstreamError>> From<E> for KeyError {
+    fn from(e: E) -> Self {
+        KeyError::Upstream(e.into())
+    }
+}
+
+async fn fetch_query_with_key<IT: ?Sized + Serialize, OT: DeserializeOwned + Debug>(
+    config: &Config,
+    api_key: &str,
+    request_body: &IT,
+) -> Result<OT, KeyError> {
+    limiter("Airstack", api_key, &config.airstack_limits)
+        .acquire()
+        .await?;
+
     let client = reqwest::Client::builder()
         .user_agent("graphql-rust/0.10.0")
         .default_headers(
@@ -22,17 +128,84 @@ pub async fn fetch_query<IT: ?Sized + Serialize, OT: DeserializeOwned + Debug>(
     // log the request_body as json string
     // println!("request_body: {:?}", serde_json::to_string(request_body).unwrap());
 
-    let res = client
-        .post("https://api.airstack.xyz/gql")
-        .json(&request_body)
-        .send()
-        .await?;
+    let res = send(
+        &config.fixtures,
+        "Airstack",
+        client.post(&config.airstack_api_url).json(&request_body),
+    )
+    .await?;
+
+    if matches!(
+        res.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::PAYMENT_REQUIRED
+    ) {
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        return Err(KeyError::RateLimited(res.status(), retry_after));
+    }
 
     // clone res to print it
     // let res_text = res.text().await?;
     // println!("res: {:?}", res_text);
     // let value = serde_json::from_str::<OT>(&res_text).unwrap();
-    let value = res.json::<OT>().await?;
+    let body = res.bytes().await?;
+    if is_rate_limited(&body) {
+        return Err(KeyError::RateLimited(StatusCode::TOO_MANY_REQUESTS, None));
+    }
+    let value =
+        serde_json::from_slice::<OT>(&body).map_err(|e| UpstreamError::InvalidResponse {
+            upstream: "Airstack".to_string(),
+            error: e.to_string(),
+        })?;
 
     Ok(value)
 }
+
+#[derive(Deserialize)]
+struct GraphQLErrors {
+    #[serde(default)]
+    errors: Vec<GraphQLError>,
+}
+
+#[derive(Deserialize)]
+struct GraphQLError {
+    #[serde(default)]
+    message: String,
+}
+
+// Airstack also reports exhausted rate limits and quotas as GraphQL errors of a
+// 200 response
+fn is_rate_limited(body: &[u8]) -> bool {
+    let errors = match serde_json::from_slice::<GraphQLErrors>(body) {
+        Ok(res) => res.errors,
+        Err(_) => return false,
+    };
+    errors.iter().any(|e| {
+        let message = e.message.to_lowercase();
+        ["rate limit", "too many requests", "quota", "credits"]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_rate_limits_reported_as_graphql_errors() {
+        let limited = br#"{"data":null,"errors":[{"message":"Rate limit exceeded, retry later"}]}"#;
+        assert!(is_rate_limited(limited));
+        let quota = br#"{"data":null,"errors":[{"message":"Monthly Quota exhausted"}]}"#;
+        assert!(is_rate_limited(quota));
+
+        let other = br#"{"data":null,"errors":[{"message":"Cannot query field \"foo\""}]}"#;
+        assert!(!is_rate_limited(other));
+        assert!(!is_rate_limited(br#"{"data":{"Socials":null}}"#));
+        assert!(!is_rate_limited(b"not json"));
+    }
+}
@@ -1,44 +1,538 @@
-use std::env;
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    hash::Hash,
+    str::FromStr,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use redis::{Client, Commands, RedisError, RedisResult};
-use serde::{de::DeserializeOwned, Serialize};
+use redis::{
+    aio::{ConnectionLike, ConnectionManager, MultiplexedConnection},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType},
+    Client, Cmd, ErrorKind, FromRedisValue, Pipeline, RedisConnectionInfo, RedisError, RedisFuture,
+    RedisResult, TlsMode, Value,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, OnceCell};
 
-// implement a general purpose redis cache -- just get and set is enough
-fn get_redis_client() -> RedisResult<Client> {
-    let host = env::var("REDIS_HOST").expect("REDIS_HOST must be set");
-    let port = env::var("REDIS_PORT").expect("REDIS_PORT must be set");
+// Redis deployment the cache talks to, selected with REDIS_MODE. TLS is enabled for
+// all of them with REDIS_PROTOCOL=rediss.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedisMode {
+    Standalone,
+    Sentinel,
+    Cluster,
+}
+
+impl FromStr for RedisMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "standalone" => Ok(RedisMode::Standalone),
+            "sentinel" => Ok(RedisMode::Sentinel),
+            "cluster" => Ok(RedisMode::Cluster),
+            _ => Err(format!("Unknown Redis mode: {}", s)),
+        }
+    }
+}
+
+impl RedisMode {
+    // reads REDIS_MODE, a typo fails at startup instead of falling back to standalone
+    pub fn from_env() -> Self {
+        env::var("REDIS_MODE")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or_else(|e| panic!("REDIS_MODE: {}", e))
+    }
+}
+
+static REDIS_MODE: OnceLock<RedisMode> = OnceLock::new();
+
+// Sets the mode parsed with the rest of the config at startup
+pub fn set_redis_mode(mode: RedisMode) {
+    let _ = REDIS_MODE.set(mode);
+}
+
+// Connections shared by every cache call. The connection manager and the cluster
+// connection reconnect on their own, the Sentinel master connection is dropped on
+// connection errors and looked up again on the next call.
+enum Cache {
+    Standalone(ConnectionManager),
+    Sentinel(Mutex<(SentinelClient, Option<MultiplexedConnection>)>),
+    Cluster(ClusterConnection),
+}
+
+enum CacheConnection {
+    Standalone(ConnectionManager),
+    Sentinel(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for CacheConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            CacheConnection::Standalone(con) => con.req_packed_command(cmd),
+            CacheConnection::Sentinel(con) => con.req_packed_command(cmd),
+            CacheConnection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            CacheConnection::Standalone(con) => con.req_packed_commands(cmd, offset, count),
+            CacheConnection::Sentinel(con) => con.req_packed_commands(cmd, offset, count),
+            CacheConnection::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            CacheConnection::Standalone(con) => con.get_db(),
+            CacheConnection::Sentinel(con) => con.get_db(),
+            CacheConnection::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+static CACHE: OnceCell<Cache> = OnceCell::const_new();
+
+// an unreachable Redis shouldn't hold up requests, they go upstream instead
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn env_nodes(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_else(|_| panic!("{} must be set", key))
+        .split(',')
+        .map(|node| node.trim().to_string())
+        .filter(|node| !node.is_empty())
+        .collect()
+}
+
+async fn build_cache() -> RedisResult<Cache> {
     let password = env::var("REDIS_PASSWORD").expect("REDIS_PASSWORD must be set");
     let username = env::var("REDIS_USERNAME").unwrap_or("default".to_string());
     let protocol = env::var("REDIS_PROTOCOL").unwrap_or("redis".to_string());
+    let mode = *REDIS_MODE.get_or_init(RedisMode::from_env);
 
-    let connection_string = format!("{}://{}:{}@{}:{}", protocol, username, password, host, port);
-    let client = redis::Client::open(connection_string)?;
-    Ok(client)
+    match mode {
+        RedisMode::Sentinel => {
+            let nodes = env_nodes("REDIS_SENTINEL_NODES")
+                .into_iter()
+                .map(|node| match env::var("REDIS_SENTINEL_PASSWORD") {
+                    Ok(sentinel_password) if !sentinel_password.is_empty() => {
+                        format!("{}://:{}@{}", protocol, sentinel_password, node)
+                    }
+                    _ => format!("{}://{}", protocol, node),
+                })
+                .collect::<Vec<_>>();
+            let master_name =
+                env::var("REDIS_SENTINEL_MASTER").expect("REDIS_SENTINEL_MASTER must be set");
+            let node_connection_info = SentinelNodeConnectionInfo {
+                tls_mode: (protocol == "rediss").then_some(TlsMode::Secure),
+                redis_connection_info: Some(RedisConnectionInfo {
+                    username: Some(username),
+                    password: Some(password),
+                    ..Default::default()
+                }),
+            };
+            let client = SentinelClient::build(
+                nodes,
+                master_name,
+                Some(node_connection_info),
+                SentinelServerType::Master,
+            )?;
+            Ok(Cache::Sentinel(Mutex::new((client, None))))
+        }
+        RedisMode::Cluster => {
+            let nodes = env_nodes("REDIS_CLUSTER_NODES")
+                .into_iter()
+                .map(|node| format!("{}://{}:{}@{}", protocol, username, password, node))
+                .collect::<Vec<_>>();
+            let client = ClusterClient::new(nodes)?;
+            Ok(Cache::Cluster(client.get_async_connection().await?))
+        }
+        RedisMode::Standalone => {
+            let host = env::var("REDIS_HOST").expect("REDIS_HOST must be set");
+            let port = env::var("REDIS_PORT").expect("REDIS_PORT must be set");
+            let connection_string =
+                format!("{}://{}:{}@{}:{}", protocol, username, password, host, port);
+            let client = Client::open(connection_string)?;
+            Ok(Cache::Standalone(client.get_connection_manager().await?))
+        }
+    }
 }
 
-pub async fn get_value<V: DeserializeOwned>(key: &str) -> RedisResult<Option<V>> {
-    let client = get_redis_client()?;
-    let mut con = client.get_connection()?;
-    let value: Option<String> = con.get(key)?;
-    match value {
-        Some(val) => {
-            let v: V = serde_json::from_str(&val).map_err(|_e| {
-                RedisError::from((
-                    redis::ErrorKind::ResponseError,
-                    "Failed to deserialize value",
-                ))
-            })?;
-            Ok(Some(v))
+async fn get_redis_connection() -> RedisResult<CacheConnection> {
+    let cache = tokio::time::timeout(CONNECT_TIMEOUT, CACHE.get_or_try_init(build_cache))
+        .await
+        .map_err(|_| RedisError::from((ErrorKind::IoError, "Timed out connecting to Redis")))??;
+    match cache {
+        Cache::Standalone(con) => Ok(CacheConnection::Standalone(con.clone())),
+        Cache::Cluster(con) => Ok(CacheConnection::Cluster(con.clone())),
+        Cache::Sentinel(sentinel) => {
+            let mut sentinel = sentinel.lock().await;
+            let (client, master) = &mut *sentinel;
+            let con = match master {
+                Some(con) => con.clone(),
+                None => {
+                    let con = tokio::time::timeout(CONNECT_TIMEOUT, client.get_async_connection())
+                        .await
+                        .map_err(|_| {
+                            RedisError::from((ErrorKind::IoError, "Timed out connecting to Redis"))
+                        })??;
+                    *master = Some(con.clone());
+                    con
+                }
+            };
+            Ok(CacheConnection::Sentinel(con))
         }
-        None => Ok(None),
     }
 }
 
+// Runs the command on the shared connection. After a failover the old Sentinel master
+// drops connections or turns read-only, so the master is looked up again.
+async fn query<T: FromRedisValue>(cmd: &Cmd) -> RedisResult<T> {
+    let mut con = get_redis_connection().await?;
+    let res = cmd.query_async(&mut con).await;
+    if let (Err(e), Some(Cache::Sentinel(sentinel))) = (&res, CACHE.get()) {
+        if e.is_io_error() || e.is_connection_dropped() || e.kind() == ErrorKind::ReadOnly {
+            sentinel.lock().await.1 = None;
+        }
+    }
+    res
+}
+
+fn deserialize<V: DeserializeOwned>(value: &str) -> RedisResult<V> {
+    serde_json::from_str(value).map_err(|_e| {
+        RedisError::from((
+            redis::ErrorKind::ResponseError,
+            "Failed to deserialize value",
+        ))
+    })
+}
+
+pub async fn get_value<V: DeserializeOwned>(key: &str) -> RedisResult<Option<V>> {
+    let value: Option<String> = query(redis::cmd("GET").arg(key)).await?;
+    value.map(|val| deserialize(&val)).transpose()
+}
+
+// Values of many keys with one MGET, `None` for missing keys and values that no
+// longer deserialize
+pub async fn get_values<V: DeserializeOwned>(keys: &[String]) -> RedisResult<Vec<Option<V>>> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    let values: Vec<Option<String>> = query(redis::cmd("MGET").arg(keys)).await?;
+    Ok(values
+        .into_iter()
+        .map(|value| value.and_then(|val| deserialize(&val).ok()))
+        .collect())
+}
+
+// Increments a counter shared by all instances, the key expires `ttl_secs` after
+// its first increment
+pub async fn incr(key: &str, ttl_secs: u64) -> RedisResult<u64> {
+    let count: u64 = query(redis::cmd("INCR").arg(key)).await?;
+    if count == 1 {
+        let _: () = query(redis::cmd("EXPIRE").arg(key).arg(ttl_secs)).await?;
+    }
+    Ok(count)
+}
+
 pub async fn set_value<V: Serialize>(key: &str, value: &V) -> RedisResult<()> {
-    let client = get_redis_client()?;
-    let mut con = client.get_connection()?;
-    // let _ = con.set_write_timeout(Some(Duration::from_secs(10)));
     let v = serde_json::to_string(value).unwrap();
-    let _: () = con.set(key, v)?;
-    Ok(())
+    query(redis::cmd("SET").arg(key).arg(v)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedData<T> {
+    pub data: T,
+    pub timestamp: u64,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataSource {
+    Cache,
+    Upstream,
+}
+
+// freshness metadata attached to every response envelope as `meta`
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Freshness {
+    pub fetched_at: u64,
+    pub source: DataSource,
+    pub stale: bool,
+}
+
+impl Freshness {
+    pub fn upstream() -> Self {
+        Self {
+            fetched_at: now_secs(),
+            source: DataSource::Upstream,
+            stale: false,
+        }
+    }
+}
+
+// Serves the cached value if it is at most `max_age` seconds old, otherwise calls `fetch`
+// and caches the result. If the upstream call fails, an older cached value is served
+// instead and marked as stale.
+pub async fn get_or_fetch<T, E, F, Fut>(
+    key: &str,
+    max_age: u64,
+    fetch: F,
+) -> Result<(Option<T>, Freshness), E>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<T>, E>>,
+{
+    let cached = get_value::<CachedData<T>>(key).await.unwrap_or(None);
+    if let Some(cd) = cached.as_ref() {
+        if now_secs().saturating_sub(cd.timestamp) <= max_age {
+            let freshness = Freshness {
+                fetched_at: cd.timestamp,
+                source: DataSource::Cache,
+                stale: false,
+            };
+            return Ok((cached.map(|cd| cd.data), freshness));
+        }
+    }
+
+    match fetch().await {
+        Ok(Some(data)) => {
+            let freshness = Freshness::upstream();
+            let cached_data = CachedData {
+                data,
+                timestamp: freshness.fetched_at,
+            };
+            let _ = set_value(key, &cached_data).await;
+            Ok((Some(cached_data.data), freshness))
+        }
+        Ok(None) => Ok((None, Freshness::upstream())),
+        Err(e) => match cached {
+            Some(cd) => {
+                let freshness = Freshness {
+                    fetched_at: cd.timestamp,
+                    source: DataSource::Cache,
+                    stale: true,
+                };
+                Ok((Some(cd.data), freshness))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+// Batch version of `get_or_fetch`. The keys that aren't cached or are too old are
+// fetched with a single call to `fetch`, which returns the data it found per key.
+// Results are returned in the order of `entries`.
+pub async fn get_or_fetch_many<K, T, E, F, Fut>(
+    entries: Vec<(K, String)>,
+    max_age: u64,
+    fetch: F,
+) -> Vec<Result<(Option<T>, Freshness), E>>
+where
+    K: Eq + Hash + Clone,
+    T: Serialize + DeserializeOwned,
+    E: Clone,
+    F: FnOnce(Vec<K>) -> Fut,
+    Fut: Future<Output = Result<HashMap<K, T>, E>>,
+{
+    let (ids, keys): (Vec<K>, Vec<String>) = entries.into_iter().unzip();
+    let cached = get_values::<CachedData<T>>(&keys)
+        .await
+        .unwrap_or_else(|_| keys.iter().map(|_| None).collect());
+
+    let results = merge_many(ids, cached, max_age, fetch).await;
+    for (key, result) in keys.iter().zip(&results) {
+        if let Ok((Some(data), freshness)) = result {
+            if freshness.source == DataSource::Upstream {
+                let cached_data = CachedData {
+                    data,
+                    timestamp: freshness.fetched_at,
+                };
+                let _ = set_value(key, &cached_data).await;
+            }
+        }
+    }
+    results
+}
+
+// Combines what was cached for each id with what `fetch` returns for the missing ones
+async fn merge_many<K, T, E, F, Fut>(
+    ids: Vec<K>,
+    cached: Vec<Option<CachedData<T>>>,
+    max_age: u64,
+    fetch: F,
+) -> Vec<Result<(Option<T>, Freshness), E>>
+where
+    K: Eq + Hash + Clone,
+    E: Clone,
+    F: FnOnce(Vec<K>) -> Fut,
+    Fut: Future<Output = Result<HashMap<K, T>, E>>,
+{
+    let now = now_secs();
+    let is_fresh = |cd: &Option<CachedData<T>>| {
+        cd.as_ref()
+            .is_some_and(|cd| now.saturating_sub(cd.timestamp) <= max_age)
+    };
+    let missing: Vec<K> = ids
+        .iter()
+        .zip(&cached)
+        .filter(|(_, cd)| !is_fresh(cd))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    let mut fetched = if missing.is_empty() {
+        Ok(HashMap::new())
+    } else {
+        fetch(missing).await
+    };
+
+    ids.into_iter()
+        .zip(cached)
+        .map(|(id, cd)| {
+            let fresh = is_fresh(&cd);
+            match (&mut fetched, cd) {
+                (_, Some(cd)) if fresh => {
+                    let freshness = Freshness {
+                        fetched_at: cd.timestamp,
+                        source: DataSource::Cache,
+                        stale: false,
+                    };
+                    Ok((Some(cd.data), freshness))
+                }
+                (Ok(found), _) => Ok((found.remove(&id), Freshness::upstream())),
+                (Err(_), Some(cd)) => {
+                    let freshness = Freshness {
+                        fetched_at: cd.timestamp,
+                        source: DataSource::Cache,
+                        stale: true,
+                    };
+                    Ok((Some(cd.data), freshness))
+                }
+                (Err(e), None) => Err(e.clone()),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(data: &str, age: u64) -> Option<CachedData<String>> {
+        Some(CachedData {
+            data: data.to_string(),
+            timestamp: now_secs() - age,
+        })
+    }
+
+    #[test]
+    fn parses_redis_modes() {
+        assert_eq!("".parse(), Ok(RedisMode::Standalone));
+        assert_eq!("Sentinel".parse(), Ok(RedisMode::Sentinel));
+        assert_eq!("cluster".parse(), Ok(RedisMode::Cluster));
+        assert!("clsuter".parse::<RedisMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn merge_many_keeps_the_order_of_the_ids() {
+        let ids = vec![1, 2, 3, 4];
+        let entries = vec![None, cached("two", 10), cached("three", 1000), None];
+        let results = merge_many(ids, entries, 60, |missing| async move {
+            assert_eq!(missing, vec![1, 3, 4]);
+            Ok::<_, ()>(HashMap::from([
+                (4, "four".to_string()),
+                (1, "one".to_string()),
+                (3, "three!".to_string()),
+            ]))
+        })
+        .await;
+
+        let data: Vec<Option<String>> = results.iter().map(|r| r.clone().unwrap().0).collect();
+        assert_eq!(
+            data,
+            vec![
+                Some("one".to_string()),
+                Some("two".to_string()),
+                Some("three!".to_string()),
+                Some("four".to_string()),
+            ]
+        );
+        let sources: Vec<DataSource> = results
+            .iter()
+            .map(|r| r.clone().unwrap().1.source)
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                DataSource::Upstream,
+                DataSource::Cache,
+                DataSource::Upstream,
+                DataSource::Upstream,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_many_skips_fetch_when_everything_is_fresh() {
+        let results = merge_many(vec![1], vec![cached("one", 0)], 60, |_| async {
+            panic!("nothing should be fetched");
+            #[allow(unreachable_code)]
+            Ok::<HashMap<u64, String>, ()>(HashMap::new())
+        })
+        .await;
+        assert_eq!(results[0].clone().unwrap().0, Some("one".to_string()));
+    }
+
+    #[tokio::test]
+    async fn merge_many_reports_not_found_for_missing_data() {
+        let results = merge_many(vec![1], vec![None], 60, |_| async {
+            Ok::<HashMap<u64, String>, ()>(HashMap::new())
+        })
+        .await;
+        let (data, freshness) = results[0].clone().unwrap();
+        assert_eq!(data, None);
+        assert_eq!(freshness.source, DataSource::Upstream);
+    }
+
+    #[tokio::test]
+    async fn merge_many_serves_stale_data_when_the_fetch_fails() {
+        let ids = vec![1, 2, 3];
+        let entries = vec![cached("one", 1000), None, cached("three", 0)];
+        let results = merge_many(ids, entries, 60, |_| async {
+            Err::<HashMap<u64, String>, _>("Airstack is down")
+        })
+        .await;
+
+        let (data, freshness) = results[0].clone().unwrap();
+        assert_eq!(data, Some("one".to_string()));
+        assert_eq!(freshness.source, DataSource::Cache);
+        assert!(freshness.stale);
+        assert_eq!(results[1].clone().unwrap_err(), "Airstack is down");
+        let (data, freshness) = results[2].clone().unwrap();
+        assert_eq!(data, Some("three".to_string()));
+        assert!(!freshness.stale);
+    }
 }
@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::routes::config::Config;
+use crate::upstream::{
+    fixtures::{send, Fixtures},
+    UpstreamError,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HubCastId {
+    pub fid: u64,
+    pub hash: String,
+}
+
+// either a URL or a quoted cast
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HubEmbed {
+    pub url: Option<String>,
+    pub cast_id: Option<HubCastId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HubCastAddBody {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub embeds: Vec<HubEmbed>,
+    pub parent_cast_id: Option<HubCastId>,
+    pub parent_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HubUserDataBody {
+    #[serde(rename = "type")]
+    pub data_type: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HubMessageData {
+    pub fid: u64,
+    // seconds since the Farcaster epoch (2021-01-01)
+    pub timestamp: u64,
+    pub cast_add_body: Option<HubCastAddBody>,
+    pub user_data_body: Option<HubUserDataBody>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HubMessage {
+    pub hash: String,
+    pub data: HubMessageData,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HubMessagesResponse {
+    #[serde(default)]
+    messages: Vec<HubMessage>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HubUserNameProof {
+    pub name: String,
+    pub fid: u64,
+    pub owner: String,
+}
+
+// profile fields a user has set, keyed by the hub's USER_DATA_TYPE_* names
+#[derive(Debug, Default, Clone)]
+pub struct HubUserData {
+    pub fid: u64,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub pfp_url: Option<String>,
+}
+
+// Client for the HTTP API of self-hosted Farcaster hubs. Hubs are tried in the
+// configured order, moving on when one is unreachable or errors.
+pub struct HubClient {
+    http: Client,
+    api_urls: Vec<String>,
+    fixtures: Fixtures,
+}
+
+impl HubClient {
+    pub fn new(config: &Config) -> Self {
+        Self::with_api_urls(config.hub_api_urls.clone(), config.fixtures.clone())
+    }
+
+    pub fn with_api_urls(api_urls: Vec<String>, fixtures: Fixtures) -> Self {
+        Self {
+            http: Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            api_urls,
+            fixtures,
+        }
+    }
+
+    pub async fn cast_by_id(
+        &self,
+        fid: u64,
+        hash: &str,
+    ) -> Result<Option<HubMessage>, UpstreamError> {
+        self.get("castById", &[("fid", &fid.to_string()), ("hash", hash)])
+            .await
+    }
+
+    // One page of the user's casts, newest first, with the token of the next page
+    pub async fn casts_by_fid(
+        &self,
+        fid: u64,
+        page_size: u32,
+        page_token: Option<&str>,
+    ) -> Result<(Vec<HubMessage>, Option<String>), UpstreamError> {
+        let fid = fid.to_string();
+        let page_size = page_size.to_string();
+        let mut query = vec![
+            ("fid", fid.as_str()),
+            ("pageSize", page_size.as_str()),
+            ("reverse", "true"),
+        ];
+        if let Some(token) = page_token {
+            query.push(("pageToken", token));
+        }
+        let resp: Option<HubMessagesResponse> = self.get("castsByFid", &query).await?;
+        Ok(match resp {
+            Some(r) => (r.messages, r.next_page_token.filter(|t| !t.is_empty())),
+            None => (Vec::new(), None),
+        })
+    }
+
+    pub async fn user_data_by_fid(&self, fid: u64) -> Result<Option<HubUserData>, UpstreamError> {
+        let resp: Option<HubMessagesResponse> = self
+            .get("userDataByFid", &[("fid", &fid.to_string())])
+            .await?;
+        let messages = match resp {
+            Some(r) if !r.messages.is_empty() => r.messages,
+            _ => return Ok(None),
+        };
+
+        let mut user = HubUserData {
+            fid,
+            ..Default::default()
+        };
+        for body in messages.into_iter().filter_map(|m| m.data.user_data_body) {
+            match body.data_type.as_str() {
+                "USER_DATA_TYPE_USERNAME" => user.username = Some(body.value),
+                "USER_DATA_TYPE_DISPLAY" => user.display_name = Some(body.value),
+                "USER_DATA_TYPE_PFP" => user.pfp_url = Some(body.value),
+                _ => {}
+            }
+        }
+        Ok(Some(user))
+    }
+
+    pub async fn user_name_proof_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<HubUserNameProof>, UpstreamError> {
+        self.get("userNameProofByName", &[("name", name)]).await
+    }
+
+    // GET request against the first hub that answers, `None` when the hub doesn't
+    // know the requested message
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<T>, UpstreamError> {
+        let mut last_error = UpstreamError::NotConfigured("HUB_API_URLS".to_string());
+        for api_url in &self.api_urls {
+            match self.get_from(api_url, path, query).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    eprintln!("Hub {} failed: {}", api_url, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn get_from<T: DeserializeOwned>(
+        &self,
+        api_url: &str,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<T>, UpstreamError> {
+        let resp = send(
+            &self.fixtures,
+            "Hub",
+            self.http
+                .get(format!("{}/v1/{}", api_url.trim_end_matches('/'), path))
+                .query(query)
+                .header("accept", "application/json"),
+        )
+        .await?;
+
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            // hubs report missing messages as a bad request with a not_found error code
+            StatusCode::BAD_REQUEST => {
+                let status = resp.status();
+                let body: Value = resp.json().await.unwrap_or(Value::Null);
+                match body["errCode"].as_str() {
+                    Some(code) if code.contains("not_found") => Ok(None),
+                    _ => Err(UpstreamError::Status {
+                        upstream: "Hub".to_string(),
+                        status,
+                    }),
+                }
+            }
+            status if !status.is_success() => Err(UpstreamError::Status {
+                upstream: "Hub".to_string(),
+                status,
+            }),
+            _ => Ok(Some(resp.json().await?)),
+        }
+    }
+}
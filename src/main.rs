@@ -5,6 +5,11 @@ use std::env;
 mod routes;
 mod airstack;
 mod cache;
+mod hub;
+mod neynar;
+mod providers;
+mod upstream;
+mod warpcast;
 
 #[tokio::main]
 async fn main() {
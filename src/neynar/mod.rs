@@ -0,0 +1,218 @@
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::cache::{get_value, now_secs, set_value, CachedData};
+use crate::routes::config::Config;
+use crate::upstream::{
+    fixtures::{send, Fixtures},
+    rate_limit::limiter,
+    rate_limit::UpstreamLimits,
+    UpstreamError,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarUser {
+    pub fid: u64,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub pfp_url: Option<String>,
+    pub follower_count: Option<u64>,
+    pub following_count: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarParentAuthor {
+    pub fid: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarCastId {
+    pub fid: u64,
+    pub hash: String,
+}
+
+// either a URL or a quoted cast
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarEmbed {
+    pub url: Option<String>,
+    pub cast_id: Option<NeynarCastId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarChannel {
+    pub id: String,
+    pub name: Option<String>,
+    pub image_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NeynarReactions {
+    #[serde(default)]
+    pub likes_count: u64,
+    #[serde(default)]
+    pub recasts_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NeynarReplies {
+    #[serde(default)]
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NeynarCast {
+    pub hash: String,
+    pub thread_hash: Option<String>,
+    pub parent_hash: Option<String>,
+    pub parent_url: Option<String>,
+    pub parent_author: Option<NeynarParentAuthor>,
+    pub author: NeynarUser,
+    #[serde(default)]
+    pub text: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub embeds: Vec<NeynarEmbed>,
+    pub channel: Option<NeynarChannel>,
+    #[serde(default)]
+    pub reactions: NeynarReactions,
+    #[serde(default)]
+    pub replies: NeynarReplies,
+}
+
+#[derive(Debug, Deserialize)]
+struct NeynarCastResponse {
+    cast: Option<NeynarCast>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NeynarUserResponse {
+    user: Option<NeynarUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NeynarBulkUsersResponse {
+    #[serde(default)]
+    users: Vec<NeynarUser>,
+}
+
+// only the hash is kept for URL lookups, since the URL of a cast never changes
+#[derive(Debug, Serialize, Deserialize)]
+struct CastHash {
+    hash: String,
+}
+
+pub struct NeynarClient {
+    http: Client,
+    api_url: String,
+    api_key: Option<String>,
+    limits: UpstreamLimits,
+    fixtures: Fixtures,
+}
+
+impl NeynarClient {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            http: Client::new(),
+            api_url: config.neynar_api_url.clone(),
+            api_key: config.neynar_api_key.clone(),
+            limits: config.neynar_limits.clone(),
+            fixtures: config.fixtures.clone(),
+        }
+    }
+
+    pub async fn cast_by_url(&self, cast_url: &str) -> Result<Option<NeynarCast>, UpstreamError> {
+        self.lookup_cast(cast_url, "url").await
+    }
+
+    pub async fn cast_by_hash(&self, hash: &str) -> Result<Option<NeynarCast>, UpstreamError> {
+        self.lookup_cast(hash, "hash").await
+    }
+
+    // Resolves a cast URL to its hash, caching the result indefinitely
+    pub async fn resolve_cast_hash(&self, cast_url: &str) -> Result<Option<String>, UpstreamError> {
+        let cache_key = format!("neynarCast/url/{}", cast_url);
+
+        let cached_data = get_value::<CachedData<CastHash>>(&cache_key)
+            .await
+            .unwrap_or(None);
+        if let Some(cd) = cached_data {
+            return Ok(Some(cd.data.hash));
+        }
+
+        let cast = self.cast_by_url(cast_url).await?;
+        if let Some(cast) = cast.as_ref() {
+            let cached_data = CachedData {
+                data: CastHash {
+                    hash: cast.hash.clone(),
+                },
+                timestamp: now_secs(),
+            };
+            let _ = set_value(&cache_key, &cached_data).await;
+        }
+        Ok(cast.map(|c| c.hash))
+    }
+
+    pub async fn user_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<NeynarUser>, UpstreamError> {
+        let user_resp: Option<NeynarUserResponse> = self
+            .get("user/by_username", &[("username", username)])
+            .await?;
+        Ok(user_resp.and_then(|r| r.user))
+    }
+
+    pub async fn user_by_fid(&self, fid: u64) -> Result<Option<NeynarUser>, UpstreamError> {
+        let users_resp: Option<NeynarBulkUsersResponse> =
+            self.get("user/bulk", &[("fids", &fid.to_string())]).await?;
+        Ok(users_resp.and_then(|r| r.users.into_iter().next()))
+    }
+
+    async fn lookup_cast(
+        &self,
+        identifier: &str,
+        identifier_type: &str,
+    ) -> Result<Option<NeynarCast>, UpstreamError> {
+        let cast_resp: Option<NeynarCastResponse> = self
+            .get(
+                "cast",
+                &[("identifier", identifier), ("type", identifier_type)],
+            )
+            .await?;
+        Ok(cast_resp.and_then(|r| r.cast))
+    }
+
+    // GET request against the Neynar API, `None` when Neynar responds with 404
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<T>, UpstreamError> {
+        let api_key = self
+            .api_key
+            .as_deref()
+            .ok_or_else(|| UpstreamError::NotConfigured("NEYNAR_API_KEY".to_string()))?;
+
+        limiter("Neynar", api_key, &self.limits).acquire().await?;
+
+        let resp = send(
+            &self.fixtures,
+            "Neynar",
+            self.http
+                .get(format!("{}/{}", self.api_url, path))
+                .query(query)
+                .header("accept", "application/json")
+                .header("api_key", api_key),
+        )
+        .await?;
+
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if !status.is_success() => Err(UpstreamError::Status {
+                upstream: "Neynar".to_string(),
+                status,
+            }),
+            _ => Ok(Some(resp.json().await?)),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use graphql_client::{GraphQLQuery, Response};
+use serde_json::Value;
+
+use crate::airstack::{batch::CastBatcher, fetch_query, AIRSTACK_PAGE_SIZE};
+use crate::providers::{
+    CastRef, CastType, Embed, FarcasterDataProvider, ProviderError, SocialScore, SocialScores,
+};
+use crate::routes::config::Config;
+
+pub struct AirstackProvider {
+    config: Arc<Config>,
+    casts: Arc<CastBatcher>,
+}
+
+impl AirstackProvider {
+    // also reported for earnings, which are read from Airstack outside of the routing
+    pub const NAME: &'static str = "Airstack";
+
+    pub fn new(config: Arc<Config>, casts: Arc<CastBatcher>) -> Self {
+        Self { config, casts }
+    }
+
+    // Aliases without inputs are left out of the query, an empty `_in` would match
+    // every profile
+    async fn social_scores_page(
+        &self,
+        handles: &[String],
+        fids: &[u64],
+    ) -> Result<SocialScores, ProviderError> {
+        let request_body =
+            FarScoresByProfilesQuery::build_query(far_scores_by_profiles_query::Variables {
+                handles: handles.to_vec(),
+                fids: fids.iter().map(|fid| fid.to_string()).collect(),
+                with_handles: !handles.is_empty(),
+                with_fids: !fids.is_empty(),
+                limit: handles.len().max(fids.len()) as i64,
+            });
+        let res = fetch_query::<_, Response<far_scores_by_profiles_query::ResponseData>>(
+            &self.config,
+            &request_body,
+        )
+        .await?;
+
+        let mut scores = SocialScores::default();
+        let to_score = |s: &far_scores_by_profiles_query::FarScoreFragment| SocialScore {
+            score: s.social_capital.social_capital_score,
+            rank: s.social_capital.social_capital_rank,
+            profile_name: Some(s.profile_name.clone()),
+        };
+        if let Some(d) = res.data {
+            for s in d.by_handle.iter().flat_map(|s| &s.social) {
+                scores
+                    .by_handle
+                    .insert(s.profile_name.to_lowercase(), to_score(s));
+            }
+            for s in d.by_fid.iter().flat_map(|s| &s.social) {
+                if let Ok(fid) = s.user_id.parse() {
+                    scores.by_fid.insert(fid, to_score(s));
+                }
+            }
+        }
+        Ok(scores)
+    }
+}
+
+pub fn to_embeds(embeds: &[Map]) -> Vec<Embed> {
+    embeds
+        .iter()
+        .map(|embed| match embed["url"].as_str() {
+            Some(url) => Embed {
+                url: Some(url.to_string()),
+            },
+            _ => Embed { url: None },
+        })
+        .collect()
+}
+
+#[async_trait]
+impl FarcasterDataProvider for AirstackProvider {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    // Airstack can only look up top-level casts by URL, not replies
+    async fn resolve_cast(&self, cast_url: &str) -> Result<Option<String>, ProviderError> {
+        let request_body = CastHashByUrlQuery::build_query(cast_hash_by_url_query::Variables {
+            url: cast_url.to_string(),
+        });
+        let res = fetch_query::<_, Response<cast_hash_by_url_query::ResponseData>>(
+            &self.config,
+            &request_body,
+        )
+        .await?;
+        Ok(res
+            .data
+            .and_then(|d| d.farcaster_casts.cast.first().map(|c| c.hash.clone())))
+    }
+
+    // lookups by hash are batched with concurrent requests for other casts
+    async fn get_embeds(&self, cast: &CastRef) -> Result<Option<Vec<Embed>>, ProviderError> {
+        let config = &self.config;
+        let embeds = match (&cast.cast_type, &cast.hash, &cast.url) {
+            (cast_type, Some(hash), _) => {
+                let batched = self.casts.load(hash).await?.unwrap_or_default();
+                batched
+                    .select::<cast_embeds_by_url_query::CastEmbedsByUrlQueryFarcasterCastsCast>(
+                        cast_type.as_ref(),
+                    )?
+                    .map(|c| to_embeds(&c.embeds))
+            }
+            (Some(CastType::Cast), None, Some(url)) => {
+                let request_body =
+                    CastEmbedsByUrlQuery::build_query(cast_embeds_by_url_query::Variables {
+                        url: url.clone(),
+                    });
+                let res = fetch_query::<_, Response<cast_embeds_by_url_query::ResponseData>>(
+                    config,
+                    &request_body,
+                )
+                .await?;
+                res.data
+                    .and_then(|d| d.farcaster_casts.cast.first().map(|c| to_embeds(&c.embeds)))
+            }
+            _ => {
+                return Err(ProviderError::InvalidRequest(
+                    "Invalid parameters".to_string(),
+                ))
+            }
+        };
+        Ok(embeds)
+    }
+
+    async fn social_score(&self, handle: &str) -> Result<Option<SocialScore>, ProviderError> {
+        let request_body = FarScoresQuery::build_query(far_scores_query::Variables {
+            handle: handle.to_string(),
+        });
+        let res =
+            fetch_query::<_, Response<far_scores_query::ResponseData>>(&self.config, &request_body)
+                .await?;
+        Ok(res
+            .data
+            .as_ref()
+            .and_then(|d| d.socials.as_ref())
+            .and_then(|s| s.social.first())
+            .map(|s| SocialScore {
+                score: s.social_capital.social_capital_score,
+                rank: s.social_capital.social_capital_rank,
+                profile_name: Some(s.profile_name.clone()),
+            }))
+    }
+
+    // by `userId`, which unlike the profile name survives renames
+    async fn social_score_by_fid(&self, fid: u64) -> Result<Option<SocialScore>, ProviderError> {
+        let request_body = FarScoresByFidQuery::build_query(far_scores_by_fid_query::Variables {
+            fid: fid.to_string(),
+        });
+        let res = fetch_query::<_, Response<far_scores_by_fid_query::ResponseData>>(
+            &self.config,
+            &request_body,
+        )
+        .await?;
+        Ok(res
+            .data
+            .as_ref()
+            .and_then(|d| d.socials.as_ref())
+            .and_then(|s| s.social.first())
+            .map(|s| SocialScore {
+                score: s.social_capital.social_capital_score,
+                rank: s.social_capital.social_capital_rank,
+                profile_name: Some(s.profile_name.clone()),
+            }))
+    }
+
+    // handles and FIDs share a query, one per page of each
+    async fn social_scores_many(
+        &self,
+        handles: &[String],
+        fids: &[u64],
+    ) -> Result<SocialScores, ProviderError> {
+        let pages = handles.len().max(fids.len()).div_ceil(AIRSTACK_PAGE_SIZE);
+        let page = |i: usize, len: usize| {
+            (i * AIRSTACK_PAGE_SIZE).min(len)..((i + 1) * AIRSTACK_PAGE_SIZE).min(len)
+        };
+        let pages = try_join_all((0..pages).map(|i| {
+            self.social_scores_page(&handles[page(i, handles.len())], &fids[page(i, fids.len())])
+        }))
+        .await?;
+
+        let mut scores = SocialScores::default();
+        for page in pages {
+            scores.by_handle.extend(page.by_handle);
+            scores.by_fid.extend(page.by_fid);
+        }
+        Ok(scores)
+    }
+}
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/cast_embeds_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct CastEmbedsByUrlQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/cast_embeds_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct CastHashByUrlQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/far_scores_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct FarScoresQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/far_scores_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct FarScoresByFidQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/far_scores_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct FarScoresByProfilesQuery;
+
+type Map = Value;
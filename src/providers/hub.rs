@@ -0,0 +1,308 @@
+use async_trait::async_trait;
+
+use crate::hub::{HubClient, HubMessage, HubUserData};
+use crate::providers::{
+    Capability, CastRef, Embed, FarcasterDataProvider, FarcasterUser, ProviderError,
+};
+use crate::warpcast::normalize_handle;
+
+// casts of the author searched for a cast URL, newest first
+const CAST_SEARCH_PAGE_SIZE: u32 = 100;
+const CAST_SEARCH_MAX_PAGES: usize = 5;
+
+pub struct HubProvider {
+    client: HubClient,
+}
+
+impl HubProvider {
+    pub fn new(client: HubClient) -> Self {
+        Self { client }
+    }
+
+    // Hubs can't look up casts by URL, so the author's recent casts are searched for
+    // the short hash of the URL
+    async fn cast_by_url(
+        &self,
+        (username, short_hash): (String, String),
+    ) -> Result<Option<HubMessage>, ProviderError> {
+        let proof = match self.client.user_name_proof_by_name(&username).await? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+
+        let mut page_token = None;
+        for _ in 0..CAST_SEARCH_MAX_PAGES {
+            let (casts, next_page_token) = self
+                .client
+                .casts_by_fid(proof.fid, CAST_SEARCH_PAGE_SIZE, page_token.as_deref())
+                .await?;
+            if let Some(cast) = casts
+                .into_iter()
+                .find(|c| c.hash.to_lowercase().starts_with(&short_hash))
+            {
+                return Ok(Some(cast));
+            }
+            page_token = match next_page_token {
+                Some(token) => Some(token),
+                None => break,
+            };
+        }
+        Ok(None)
+    }
+}
+
+// username and lowercased short hash of a https://warpcast.com/<username>/<short hash>
+// URL, other URLs are left to other providers
+fn parse_cast_url(cast_url: &str) -> Option<(String, String)> {
+    let path = cast_url.strip_prefix("https://warpcast.com/")?;
+    let (username, short_hash) = path.trim_end_matches('/').split_once('/')?;
+    let short_hash = short_hash.to_lowercase();
+    let hex = short_hash.strip_prefix("0x")?;
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((normalize_handle(username)?, short_hash))
+}
+
+// hubs don't track followers, so counts are left unset
+fn to_farcaster_user(user: HubUserData) -> FarcasterUser {
+    FarcasterUser {
+        fid: user.fid,
+        username: user.username,
+        display_name: user.display_name,
+        pfp_url: user.pfp_url,
+        follower_count: None,
+        following_count: None,
+    }
+}
+
+#[async_trait]
+impl FarcasterDataProvider for HubProvider {
+    fn name(&self) -> &'static str {
+        "Hub"
+    }
+
+    async fn resolve_cast(&self, cast_url: &str) -> Result<Option<String>, ProviderError> {
+        let url = parse_cast_url(cast_url).ok_or(self.unsupported(Capability::ResolveCast))?;
+        Ok(self.cast_by_url(url).await?.map(|cast| cast.hash))
+    }
+
+    // hubs index casts by author, so the author's fid is needed alongside the hash
+    async fn get_embeds(&self, cast: &CastRef) -> Result<Option<Vec<Embed>>, ProviderError> {
+        let url = cast.url.as_deref().and_then(parse_cast_url);
+        let message = match (cast.fid, &cast.hash, url) {
+            (Some(fid), Some(hash), _) => self.client.cast_by_id(fid, hash).await?,
+            (_, _, Some(url)) => self.cast_by_url(url).await?,
+            _ => return Err(self.unsupported(Capability::Embeds)),
+        };
+        Ok(message.and_then(|m| m.data.cast_add_body).map(|body| {
+            body.embeds
+                .into_iter()
+                .map(|embed| Embed { url: embed.url })
+                .collect()
+        }))
+    }
+
+    async fn user_by_handle(&self, handle: &str) -> Result<Option<FarcasterUser>, ProviderError> {
+        let username = normalize_handle(handle)
+            .ok_or_else(|| ProviderError::InvalidRequest("Invalid handle".to_string()))?;
+        let proof = match self.client.user_name_proof_by_name(&username).await? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+        let user = self.client.user_data_by_fid(proof.fid).await?;
+        Ok(Some(to_farcaster_user(user.unwrap_or(HubUserData {
+            fid: proof.fid,
+            username: Some(proof.name),
+            ..Default::default()
+        }))))
+    }
+
+    async fn user_by_fid(&self, fid: u64) -> Result<Option<FarcasterUser>, ProviderError> {
+        let user = self.client.user_data_by_fid(fid).await?;
+        Ok(user.map(to_farcaster_user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use axum::{extract::Query, http::StatusCode, routing::get, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::upstream::fixtures::{FixtureMode, Fixtures};
+
+    const CAST_HASH: &str = "0xa1b2c3d4e5f60718293a4b5c6d7e8f9012345678";
+
+    fn not_found() -> (StatusCode, Json<Value>) {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"errCode": "bad_request.not_found"})),
+        )
+    }
+
+    fn cast_message(hash: &str, embed: &str) -> Value {
+        json!({
+            "hash": hash,
+            "data": {
+                "fid": 3,
+                "timestamp": 100,
+                "castAddBody": {"text": "gm", "embeds": [{"url": embed}]},
+            },
+        })
+    }
+
+    // A hub knowing user 3 (dwr) with two pages of casts, the wanted one on the second
+    async fn stub_hub() -> String {
+        type Params = Query<HashMap<String, String>>;
+        let app = Router::new()
+            .route(
+                "/v1/castById",
+                get(|Query(q): Params| async move {
+                    match (q["fid"].as_str(), q["hash"].as_str()) {
+                        ("3", CAST_HASH) => Ok(Json(cast_message(CAST_HASH, "https://a.xyz"))),
+                        _ => Err(not_found()),
+                    }
+                }),
+            )
+            .route(
+                "/v1/castsByFid",
+                get(|Query(q): Params| async move {
+                    let page = match q.get("pageToken").map(|t| t.as_str()) {
+                        None => json!({
+                            "messages": [cast_message("0xffff0000", "https://b.xyz")],
+                            "nextPageToken": "page2",
+                        }),
+                        Some("page2") => json!({
+                            "messages": [cast_message(CAST_HASH, "https://a.xyz")],
+                            "nextPageToken": "",
+                        }),
+                        Some(_) => json!({"messages": []}),
+                    };
+                    Json(page)
+                }),
+            )
+            .route(
+                "/v1/userNameProofByName",
+                get(|Query(q): Params| async move {
+                    match q["name"].as_str() {
+                        "dwr" => Ok(Json(json!({"name": "dwr", "fid": 3, "owner": "0x1"}))),
+                        _ => Err(not_found()),
+                    }
+                }),
+            )
+            .route(
+                "/v1/userDataByFid",
+                get(|Query(q): Params| async move {
+                    match q["fid"].as_str() {
+                        "3" => Ok(Json(json!({"messages": [
+                            {"hash": "0x1", "data": {"fid": 3, "timestamp": 1, "userDataBody":
+                                {"type": "USER_DATA_TYPE_USERNAME", "value": "dwr"}}},
+                            {"hash": "0x2", "data": {"fid": 3, "timestamp": 1, "userDataBody":
+                                {"type": "USER_DATA_TYPE_DISPLAY", "value": "Dan"}}},
+                        ]}))),
+                        _ => Err((StatusCode::NOT_FOUND, Json(json!({})))),
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn provider() -> HubProvider {
+        let fixtures = Fixtures {
+            mode: FixtureMode::Off,
+            dir: PathBuf::new(),
+        };
+        HubProvider::new(HubClient::with_api_urls(vec![stub_hub().await], fixtures))
+    }
+
+    fn cast_ref(hash: Option<&str>, url: Option<&str>, fid: Option<u64>) -> CastRef {
+        CastRef {
+            hash: hash.map(|h| h.to_string()),
+            url: url.map(|u| u.to_string()),
+            cast_type: None,
+            fid,
+        }
+    }
+
+    fn urls(embeds: Option<Vec<Embed>>) -> Option<Vec<String>> {
+        embeds.map(|e| e.into_iter().filter_map(|e| e.url).collect())
+    }
+
+    #[tokio::test]
+    async fn looks_up_embeds_by_fid_and_hash() {
+        let hub = provider().await;
+        let embeds = hub
+            .get_embeds(&cast_ref(Some(CAST_HASH), None, Some(3)))
+            .await
+            .unwrap();
+        assert_eq!(urls(embeds), Some(vec!["https://a.xyz".to_string()]));
+
+        let missing = hub
+            .get_embeds(&cast_ref(Some("0xdead"), None, Some(3)))
+            .await
+            .unwrap();
+        assert!(missing.is_none());
+
+        let without_fid = hub.get_embeds(&cast_ref(Some(CAST_HASH), None, None)).await;
+        assert!(matches!(
+            without_fid,
+            Err(ProviderError::Unsupported { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn finds_casts_by_url_in_the_authors_casts() {
+        let hub = provider().await;
+        let url = "https://warpcast.com/dwr/0xa1b2c3d4";
+        assert_eq!(
+            hub.resolve_cast(url).await.unwrap(),
+            Some(CAST_HASH.to_string())
+        );
+        let embeds = hub
+            .get_embeds(&cast_ref(None, Some(url), None))
+            .await
+            .unwrap();
+        assert_eq!(urls(embeds), Some(vec!["https://a.xyz".to_string()]));
+
+        let unknown_cast = "https://warpcast.com/dwr/0x99999999";
+        assert_eq!(hub.resolve_cast(unknown_cast).await.unwrap(), None);
+        let unknown_user = "https://warpcast.com/nobody/0xa1b2c3d4";
+        assert_eq!(hub.resolve_cast(unknown_user).await.unwrap(), None);
+        let other_client = hub.resolve_cast("https://example.com/dwr/0xa1b2c3d4").await;
+        assert!(matches!(
+            other_client,
+            Err(ProviderError::Unsupported { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn looks_up_users_by_handle_and_fid() {
+        let hub = provider().await;
+        let user = hub.user_by_handle("@DWR").await.unwrap().unwrap();
+        assert_eq!(user.fid, 3);
+        assert_eq!(user.username.as_deref(), Some("dwr"));
+        assert_eq!(user.display_name.as_deref(), Some("Dan"));
+
+        assert!(hub.user_by_handle("nobody").await.unwrap().is_none());
+        assert_eq!(hub.user_by_fid(3).await.unwrap().unwrap().fid, 3);
+        assert!(hub.user_by_fid(4).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_warpcast_cast_urls() {
+        assert_eq!(
+            parse_cast_url("https://warpcast.com/dwr/0xA1B2c3d4"),
+            Some(("dwr".to_string(), "0xa1b2c3d4".to_string()))
+        );
+        assert_eq!(parse_cast_url("https://warpcast.com/dwr"), None);
+        assert_eq!(parse_cast_url("https://warpcast.com/dwr/0xa1/x"), None);
+        assert_eq!(parse_cast_url("https://warpcast.com/dwr/0xzz"), None);
+        assert_eq!(parse_cast_url("https://example.com/dwr/0xa1b2c3d4"), None);
+    }
+}
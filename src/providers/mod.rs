@@ -0,0 +1,685 @@
+use std::{collections::HashMap, env, fmt, future::Future, pin::Pin, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::airstack::batch::CastBatcher;
+use crate::hub::HubClient;
+use crate::neynar::NeynarClient;
+use crate::routes::config::Config;
+use crate::upstream::UpstreamError;
+use crate::warpcast::WarpcastClient;
+
+pub mod airstack;
+mod hub;
+mod neynar;
+mod warpcast;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum CastType {
+    Cast,
+    Reply,
+}
+
+// A cast as identified by the client, by hash and/or URL
+#[derive(Debug, Clone)]
+pub struct CastRef {
+    pub hash: Option<String>,
+    pub url: Option<String>,
+    pub cast_type: Option<CastType>,
+    // author of the cast, only needed by hubs
+    pub fid: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Embed {
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FarcasterUser {
+    pub fid: u64,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub pfp_url: Option<String>,
+    pub follower_count: Option<u64>,
+    pub following_count: Option<u64>,
+}
+
+// A cast as returned by GET /casts/:hashOrUrl, the same whichever provider served it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Cast {
+    pub hash: String,
+    pub author: FarcasterUser,
+    pub text: String,
+    // ISO 8601
+    pub timestamp: String,
+    pub parent: Option<CastParent>,
+    pub channel: Option<CastChannel>,
+    pub embeds: Vec<CastEmbed>,
+    pub reactions: CastReactions,
+}
+
+// What a reply replies to, a cast or a URL such as a channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CastParent {
+    pub hash: Option<String>,
+    pub fid: Option<u64>,
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CastChannel {
+    pub id: String,
+    pub name: Option<String>,
+    pub image_url: Option<String>,
+}
+
+// either a URL or a quoted cast
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CastEmbed {
+    pub url: Option<String>,
+    pub cast_hash: Option<String>,
+    pub cast_fid: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CastReactions {
+    pub likes: u64,
+    pub recasts: u64,
+    pub replies: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocialScore {
+    pub score: f64,
+    pub rank: i64,
+    pub profile_name: Option<String>,
+}
+
+// Scores of many users at once, users without a score are left out
+#[derive(Debug, Default)]
+pub struct SocialScores {
+    pub by_handle: HashMap<String, SocialScore>,
+    pub by_fid: HashMap<u64, SocialScore>,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    Unsupported {
+        provider: &'static str,
+        capability: Capability,
+    },
+    InvalidRequest(String),
+    Upstream(UpstreamError),
+}
+
+impl ProviderError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProviderError::Unsupported { .. } => StatusCode::NOT_IMPLEMENTED,
+            ProviderError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ProviderError::Upstream(e) => e.status_code(),
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Unsupported {
+                provider,
+                capability,
+            } => write!(f, "{} does not support {}", provider, capability),
+            ProviderError::InvalidRequest(msg) => write!(f, "{}", msg),
+            ProviderError::Upstream(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<UpstreamError> for ProviderError {
+    fn from(e: UpstreamError) -> Self {
+        ProviderError::Upstream(e)
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        ProviderError::Upstream(UpstreamError::Http(e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    ResolveCast,
+    Cast,
+    Embeds,
+    User,
+    SocialScore,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::ResolveCast => "cast resolution",
+            Capability::Cast => "cast lookup",
+            Capability::Embeds => "cast embeds",
+            Capability::User => "user lookup",
+            Capability::SocialScore => "social scores",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Source of Farcaster data. Backends only implement the capabilities they support,
+// the rest fall back to an `Unsupported` error.
+#[async_trait]
+pub trait FarcasterDataProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn unsupported(&self, capability: Capability) -> ProviderError {
+        ProviderError::Unsupported {
+            provider: self.name(),
+            capability,
+        }
+    }
+
+    // cast URL to cast hash
+    async fn resolve_cast(&self, _cast_url: &str) -> Result<Option<String>, ProviderError> {
+        Err(self.unsupported(Capability::ResolveCast))
+    }
+
+    async fn get_cast(&self, _cast: &CastRef) -> Result<Option<Cast>, ProviderError> {
+        Err(self.unsupported(Capability::Cast))
+    }
+
+    async fn get_embeds(&self, _cast: &CastRef) -> Result<Option<Vec<Embed>>, ProviderError> {
+        Err(self.unsupported(Capability::Embeds))
+    }
+
+    async fn user_by_handle(&self, _handle: &str) -> Result<Option<FarcasterUser>, ProviderError> {
+        Err(self.unsupported(Capability::User))
+    }
+
+    async fn user_by_fid(&self, _fid: u64) -> Result<Option<FarcasterUser>, ProviderError> {
+        Err(self.unsupported(Capability::User))
+    }
+
+    async fn social_score(&self, _handle: &str) -> Result<Option<SocialScore>, ProviderError> {
+        Err(self.unsupported(Capability::SocialScore))
+    }
+
+    async fn social_score_by_fid(&self, _fid: u64) -> Result<Option<SocialScore>, ProviderError> {
+        Err(self.unsupported(Capability::SocialScore))
+    }
+
+    // one lookup per user, for providers that can't batch them
+    async fn social_scores_many(
+        &self,
+        handles: &[String],
+        fids: &[u64],
+    ) -> Result<SocialScores, ProviderError> {
+        let mut scores = SocialScores::default();
+        for handle in handles {
+            if let Some(score) = self.social_score(handle).await? {
+                scores.by_handle.insert(handle.clone(), score);
+            }
+        }
+        for fid in fids {
+            if let Some(score) = self.social_score_by_fid(*fid).await? {
+                scores.by_fid.insert(*fid, score);
+            }
+        }
+        Ok(scores)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProviderKind {
+    Airstack,
+    Neynar,
+    Warpcast,
+    Hub,
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "airstack" => Ok(ProviderKind::Airstack),
+            "neynar" => Ok(ProviderKind::Neynar),
+            "warpcast" => Ok(ProviderKind::Warpcast),
+            "hub" => Ok(ProviderKind::Hub),
+            _ => Err(format!("Unknown provider: {}", s)),
+        }
+    }
+}
+
+impl ProviderKind {
+    // Capabilities the provider implements, has to be kept in sync with its
+    // `FarcasterDataProvider` impl
+    pub fn capabilities(&self) -> &'static [Capability] {
+        match self {
+            ProviderKind::Airstack => &[
+                Capability::ResolveCast,
+                Capability::Embeds,
+                Capability::SocialScore,
+            ],
+            ProviderKind::Neynar => &[
+                Capability::ResolveCast,
+                Capability::Cast,
+                Capability::Embeds,
+                Capability::User,
+            ],
+            ProviderKind::Warpcast => &[Capability::User],
+            ProviderKind::Hub => &[
+                Capability::ResolveCast,
+                Capability::Embeds,
+                Capability::User,
+            ],
+        }
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProviderKind::Airstack => "airstack",
+            ProviderKind::Neynar => "neynar",
+            ProviderKind::Warpcast => "warpcast",
+            ProviderKind::Hub => "hub",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Ordered providers per capability, e.g. PROVIDER_EMBEDS=airstack,neynar. Later
+// providers are only asked when the earlier ones fail.
+#[derive(Debug, Clone)]
+pub struct ProviderRouting {
+    pub resolve_cast: Vec<ProviderKind>,
+    pub cast: Vec<ProviderKind>,
+    pub embeds: Vec<ProviderKind>,
+    pub user: Vec<ProviderKind>,
+    pub social_score: Vec<ProviderKind>,
+}
+
+impl ProviderRouting {
+    pub fn from_env() -> Self {
+        let var = |key: &str, default: &[ProviderKind]| match env::var(key) {
+            Ok(v) => v
+                .split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| p.parse().unwrap_or_else(|e| panic!("{}: {}", key, e)))
+                .collect(),
+            Err(_) => default.to_vec(),
+        };
+        let routing = Self {
+            resolve_cast: var(
+                "PROVIDER_RESOLVE_CAST",
+                &[ProviderKind::Neynar, ProviderKind::Airstack],
+            ),
+            cast: var("PROVIDER_CAST", &[ProviderKind::Neynar]),
+            embeds: var(
+                "PROVIDER_EMBEDS",
+                &[ProviderKind::Airstack, ProviderKind::Neynar],
+            ),
+            user: var(
+                "PROVIDER_USER",
+                &[ProviderKind::Warpcast, ProviderKind::Neynar],
+            ),
+            social_score: var("PROVIDER_SOCIAL_SCORE", &[ProviderKind::Airstack]),
+        };
+        routing.validate().unwrap_or_else(|e| panic!("{}", e));
+        routing
+    }
+
+    // Rejects chains naming a provider that can't serve the capability, which would
+    // otherwise only show up as missing data at request time
+    pub fn validate(&self) -> Result<(), String> {
+        let chains = [
+            (
+                "PROVIDER_RESOLVE_CAST",
+                &self.resolve_cast,
+                Capability::ResolveCast,
+            ),
+            ("PROVIDER_CAST", &self.cast, Capability::Cast),
+            ("PROVIDER_EMBEDS", &self.embeds, Capability::Embeds),
+            ("PROVIDER_USER", &self.user, Capability::User),
+            (
+                "PROVIDER_SOCIAL_SCORE",
+                &self.social_score,
+                Capability::SocialScore,
+            ),
+        ];
+        for (key, chain, capability) in chains {
+            if let Some(kind) = chain
+                .iter()
+                .find(|kind| !kind.capabilities().contains(&capability))
+            {
+                return Err(format!("{}: {} does not support {}", key, kind, capability));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Data along with the name of the provider that served it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Provided<T> {
+    pub data: T,
+    pub provider: String,
+}
+
+type ProviderFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<Option<T>, ProviderError>> + Send + 'a>>;
+
+// Dispatches each capability to the providers configured for it
+pub struct Providers {
+    airstack: Arc<dyn FarcasterDataProvider>,
+    neynar: Arc<dyn FarcasterDataProvider>,
+    warpcast: Arc<dyn FarcasterDataProvider>,
+    hub: Arc<dyn FarcasterDataProvider>,
+    routing: ProviderRouting,
+}
+
+impl Providers {
+    pub fn new(config: Arc<Config>, casts: Arc<CastBatcher>) -> Self {
+        Self {
+            neynar: Arc::new(neynar::NeynarProvider::new(NeynarClient::new(&config))),
+            warpcast: Arc::new(warpcast::WarpcastProvider::new(WarpcastClient::new(
+                &config,
+            ))),
+            hub: Arc::new(hub::HubProvider::new(HubClient::new(&config))),
+            routing: config.providers.clone(),
+            airstack: Arc::new(airstack::AirstackProvider::new(config, casts)),
+        }
+    }
+
+    fn get(&self, kind: ProviderKind) -> &dyn FarcasterDataProvider {
+        match kind {
+            ProviderKind::Airstack => self.airstack.as_ref(),
+            ProviderKind::Neynar => self.neynar.as_ref(),
+            ProviderKind::Warpcast => self.warpcast.as_ref(),
+            ProviderKind::Hub => self.hub.as_ref(),
+        }
+    }
+
+    // Asks the providers in order until one returns data. Not found is only reported
+    // when no provider failed, otherwise the last failure is returned. Providers that
+    // can't serve the request or lack credentials are skipped.
+    async fn with_fallback<'a, T>(
+        &'a self,
+        chain: &'a [ProviderKind],
+        call: impl Fn(&'a dyn FarcasterDataProvider) -> ProviderFuture<'a, T>,
+    ) -> Result<Option<Provided<T>>, ProviderError> {
+        let mut last_error = None;
+        for kind in chain {
+            let provider = self.get(*kind);
+            match call(provider).await {
+                Ok(Some(data)) => {
+                    return Ok(Some(Provided {
+                        data,
+                        provider: provider.name().to_string(),
+                    }))
+                }
+                Ok(None) => {}
+                Err(ProviderError::InvalidRequest(msg)) => {
+                    return Err(ProviderError::InvalidRequest(msg))
+                }
+                Err(ProviderError::Unsupported { .. })
+                | Err(ProviderError::Upstream(UpstreamError::NotConfigured(_))) => {}
+                Err(e) => {
+                    eprintln!(
+                        "{} failed, trying the next provider: {}",
+                        provider.name(),
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn resolve_cast(
+        &self,
+        cast_url: &str,
+    ) -> Result<Option<Provided<String>>, ProviderError> {
+        self.with_fallback(&self.routing.resolve_cast, |p| p.resolve_cast(cast_url))
+            .await
+    }
+
+    pub async fn get_cast(&self, cast: &CastRef) -> Result<Option<Provided<Cast>>, ProviderError> {
+        self.with_fallback(&self.routing.cast, |p| p.get_cast(cast))
+            .await
+    }
+
+    pub async fn get_embeds(
+        &self,
+        cast: &CastRef,
+    ) -> Result<Option<Provided<Vec<Embed>>>, ProviderError> {
+        self.with_fallback(&self.routing.embeds, |p| p.get_embeds(cast))
+            .await
+    }
+
+    pub async fn user_by_handle(
+        &self,
+        handle: &str,
+    ) -> Result<Option<Provided<FarcasterUser>>, ProviderError> {
+        self.with_fallback(&self.routing.user, |p| p.user_by_handle(handle))
+            .await
+    }
+
+    pub async fn user_by_fid(
+        &self,
+        fid: u64,
+    ) -> Result<Option<Provided<FarcasterUser>>, ProviderError> {
+        self.with_fallback(&self.routing.user, |p| p.user_by_fid(fid))
+            .await
+    }
+
+    pub async fn social_score(
+        &self,
+        handle: &str,
+    ) -> Result<Option<Provided<SocialScore>>, ProviderError> {
+        self.with_fallback(&self.routing.social_score, |p| p.social_score(handle))
+            .await
+    }
+
+    pub async fn social_score_by_fid(
+        &self,
+        fid: u64,
+    ) -> Result<Option<Provided<SocialScore>>, ProviderError> {
+        self.with_fallback(&self.routing.social_score, |p| p.social_score_by_fid(fid))
+            .await
+    }
+
+    // served as a whole by the first provider that answers
+    pub async fn social_scores_many(
+        &self,
+        handles: &[String],
+        fids: &[u64],
+    ) -> Result<Option<Provided<SocialScores>>, ProviderError> {
+        self.with_fallback(&self.routing.social_score, |p| {
+            Box::pin(async move { p.social_scores_many(handles, fids).await.map(Some) })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_routing() -> ProviderRouting {
+        ProviderRouting {
+            resolve_cast: vec![ProviderKind::Neynar, ProviderKind::Airstack],
+            cast: vec![ProviderKind::Neynar],
+            embeds: vec![ProviderKind::Airstack, ProviderKind::Neynar],
+            user: vec![ProviderKind::Warpcast, ProviderKind::Neynar],
+            social_score: vec![ProviderKind::Airstack],
+        }
+    }
+
+    #[test]
+    fn accepts_the_default_routing() {
+        assert_eq!(default_routing().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_providers_without_the_capability() {
+        let routing = ProviderRouting {
+            embeds: vec![ProviderKind::Airstack, ProviderKind::Warpcast],
+            ..default_routing()
+        };
+        assert_eq!(
+            routing.validate(),
+            Err("PROVIDER_EMBEDS: warpcast does not support cast embeds".to_string())
+        );
+
+        let routing = ProviderRouting {
+            social_score: vec![ProviderKind::Neynar],
+            ..default_routing()
+        };
+        assert!(routing.validate().is_err());
+    }
+
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        Found,
+        NotFound,
+        Failed,
+        Unsupported,
+        NotConfigured,
+        InvalidRequest,
+    }
+
+    struct FakeProvider {
+        name: &'static str,
+        outcome: Outcome,
+    }
+
+    #[async_trait]
+    impl FarcasterDataProvider for FakeProvider {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn user_by_fid(&self, fid: u64) -> Result<Option<FarcasterUser>, ProviderError> {
+            match self.outcome {
+                Outcome::Found => Ok(Some(FarcasterUser {
+                    fid,
+                    username: None,
+                    display_name: None,
+                    pfp_url: None,
+                    follower_count: None,
+                    following_count: None,
+                })),
+                Outcome::NotFound => Ok(None),
+                Outcome::Failed => Err(ProviderError::Upstream(UpstreamError::Status {
+                    upstream: self.name.to_string(),
+                    status: reqwest::StatusCode::BAD_GATEWAY,
+                })),
+                Outcome::Unsupported => Err(self.unsupported(Capability::User)),
+                Outcome::NotConfigured => Err(ProviderError::Upstream(
+                    UpstreamError::NotConfigured("API_KEY".to_string()),
+                )),
+                Outcome::InvalidRequest => {
+                    Err(ProviderError::InvalidRequest("Invalid".to_string()))
+                }
+            }
+        }
+    }
+
+    // routes user lookups through the fake providers in the given order
+    fn providers(outcomes: &[Outcome]) -> Providers {
+        let kinds = [
+            ProviderKind::Airstack,
+            ProviderKind::Neynar,
+            ProviderKind::Warpcast,
+            ProviderKind::Hub,
+        ];
+        let names = ["Airstack", "Neynar", "Warpcast", "Hub"];
+        let fake = |i: usize| -> Arc<dyn FarcasterDataProvider> {
+            Arc::new(FakeProvider {
+                name: names[i],
+                outcome: outcomes.get(i).copied().unwrap_or(Outcome::NotFound),
+            })
+        };
+        Providers {
+            airstack: fake(0),
+            neynar: fake(1),
+            warpcast: fake(2),
+            hub: fake(3),
+            routing: ProviderRouting {
+                user: kinds[..outcomes.len()].to_vec(),
+                ..default_routing()
+            },
+        }
+    }
+
+    async fn lookup(outcomes: &[Outcome]) -> Result<Option<String>, ProviderError> {
+        providers(outcomes)
+            .user_by_fid(1)
+            .await
+            .map(|user| user.map(|u| u.provider))
+    }
+
+    #[tokio::test]
+    async fn falls_back_until_a_provider_has_the_data() {
+        let found = lookup(&[Outcome::Failed, Outcome::NotFound, Outcome::Found]).await;
+        assert_eq!(found.unwrap(), Some("Warpcast".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reports_the_last_failure_over_not_found() {
+        let res = lookup(&[Outcome::Failed, Outcome::NotFound]).await;
+        assert!(matches!(
+            res,
+            Err(ProviderError::Upstream(UpstreamError::Status { upstream, .. })) if upstream == "Airstack"
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_unsupported_and_unconfigured_providers() {
+        let res = lookup(&[
+            Outcome::Unsupported,
+            Outcome::NotConfigured,
+            Outcome::NotFound,
+        ])
+        .await;
+        assert!(matches!(res, Ok(None)));
+
+        let res = lookup(&[
+            Outcome::NotConfigured,
+            Outcome::Failed,
+            Outcome::Unsupported,
+        ])
+        .await;
+        assert!(matches!(
+            res,
+            Err(ProviderError::Upstream(UpstreamError::Status { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stops_at_invalid_requests() {
+        let res = lookup(&[Outcome::InvalidRequest, Outcome::Found]).await;
+        assert!(matches!(res, Err(ProviderError::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+
+use crate::neynar::{NeynarCast, NeynarClient, NeynarUser};
+use crate::providers::{
+    Cast, CastChannel, CastEmbed, CastParent, CastReactions, CastRef, Embed, FarcasterDataProvider,
+    FarcasterUser, ProviderError,
+};
+use crate::warpcast::normalize_handle;
+
+pub struct NeynarProvider {
+    client: NeynarClient,
+}
+
+impl NeynarProvider {
+    pub fn new(client: NeynarClient) -> Self {
+        Self { client }
+    }
+}
+
+fn to_farcaster_user(user: NeynarUser) -> FarcasterUser {
+    FarcasterUser {
+        fid: user.fid,
+        username: user.username,
+        display_name: user.display_name,
+        pfp_url: user.pfp_url,
+        follower_count: user.follower_count,
+        following_count: user.following_count,
+    }
+}
+
+fn to_cast(cast: NeynarCast) -> Cast {
+    let parent = match (&cast.parent_hash, &cast.parent_url) {
+        (None, None) => None,
+        _ => Some(CastParent {
+            hash: cast.parent_hash,
+            fid: cast.parent_author.and_then(|a| a.fid),
+            url: cast.parent_url,
+        }),
+    };
+    Cast {
+        hash: cast.hash,
+        author: to_farcaster_user(cast.author),
+        text: cast.text,
+        timestamp: cast.timestamp,
+        parent,
+        channel: cast.channel.map(|c| CastChannel {
+            id: c.id,
+            name: c.name,
+            image_url: c.image_url,
+        }),
+        embeds: cast
+            .embeds
+            .into_iter()
+            .map(|embed| CastEmbed {
+                url: embed.url,
+                cast_fid: embed.cast_id.as_ref().map(|c| c.fid),
+                cast_hash: embed.cast_id.map(|c| c.hash),
+            })
+            .collect(),
+        reactions: CastReactions {
+            likes: cast.reactions.likes_count,
+            recasts: cast.reactions.recasts_count,
+            replies: cast.replies.count,
+        },
+    }
+}
+
+#[async_trait]
+impl FarcasterDataProvider for NeynarProvider {
+    fn name(&self) -> &'static str {
+        "Neynar"
+    }
+
+    async fn resolve_cast(&self, cast_url: &str) -> Result<Option<String>, ProviderError> {
+        Ok(self.client.resolve_cast_hash(cast_url).await?)
+    }
+
+    async fn get_cast(&self, cast: &CastRef) -> Result<Option<Cast>, ProviderError> {
+        let cast = match (&cast.hash, &cast.url) {
+            (Some(hash), _) => self.client.cast_by_hash(hash).await?,
+            (None, Some(url)) => self.client.cast_by_url(url).await?,
+            (None, None) => {
+                return Err(ProviderError::InvalidRequest(
+                    "Invalid parameters".to_string(),
+                ))
+            }
+        };
+        Ok(cast.map(to_cast))
+    }
+
+    async fn get_embeds(&self, cast: &CastRef) -> Result<Option<Vec<Embed>>, ProviderError> {
+        let cast = match (&cast.hash, &cast.url) {
+            (Some(hash), _) => self.client.cast_by_hash(hash).await?,
+            (None, Some(url)) => self.client.cast_by_url(url).await?,
+            (None, None) => {
+                return Err(ProviderError::InvalidRequest(
+                    "Invalid parameters".to_string(),
+                ))
+            }
+        };
+        Ok(cast.map(|c| {
+            c.embeds
+                .into_iter()
+                .map(|embed| Embed { url: embed.url })
+                .collect()
+        }))
+    }
+
+    async fn user_by_handle(&self, handle: &str) -> Result<Option<FarcasterUser>, ProviderError> {
+        let username = normalize_handle(handle)
+            .ok_or_else(|| ProviderError::InvalidRequest("Invalid handle".to_string()))?;
+        let user = self.client.user_by_username(&username).await?;
+        Ok(user.map(to_farcaster_user))
+    }
+
+    async fn user_by_fid(&self, fid: u64) -> Result<Option<FarcasterUser>, ProviderError> {
+        let user = self.client.user_by_fid(fid).await?;
+        Ok(user.map(to_farcaster_user))
+    }
+}
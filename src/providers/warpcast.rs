@@ -0,0 +1,49 @@
+use async_trait::async_trait;
+
+use crate::providers::{FarcasterDataProvider, FarcasterUser, ProviderError};
+use crate::warpcast::{WarpcastClient, WarpcastError, WarpcastUser};
+
+pub struct WarpcastProvider {
+    client: WarpcastClient,
+}
+
+impl WarpcastProvider {
+    pub fn new(client: WarpcastClient) -> Self {
+        Self { client }
+    }
+}
+
+fn to_farcaster_user(
+    user: Result<WarpcastUser, WarpcastError>,
+) -> Result<Option<FarcasterUser>, ProviderError> {
+    match user {
+        Ok(user) => Ok(Some(FarcasterUser {
+            fid: user.fid,
+            username: user.username,
+            display_name: user.display_name,
+            pfp_url: user.pfp.and_then(|p| p.url),
+            follower_count: Some(user.follower_count),
+            following_count: Some(user.following_count),
+        })),
+        Err(WarpcastError::NotFound(_)) => Ok(None),
+        Err(WarpcastError::InvalidHandle) => {
+            Err(ProviderError::InvalidRequest("Invalid handle".to_string()))
+        }
+        Err(WarpcastError::Upstream(e)) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl FarcasterDataProvider for WarpcastProvider {
+    fn name(&self) -> &'static str {
+        "Warpcast"
+    }
+
+    async fn user_by_handle(&self, handle: &str) -> Result<Option<FarcasterUser>, ProviderError> {
+        to_farcaster_user(self.client.user_by_handle(handle).await)
+    }
+
+    async fn user_by_fid(&self, fid: u64) -> Result<Option<FarcasterUser>, ProviderError> {
+        to_farcaster_user(self.client.user_by_fid(fid).await)
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde_json::json;
+
+use crate::airstack::key_pool::key_pool;
+use crate::routes::config::Config;
+
+// Usage of each Airstack key since the server started, keys are truncated
+pub async fn get_airstack_keys(
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    Ok(Json(json!({ "data": key_pool(&config).usage() })))
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    routes::{
+        cast_earnings_handler::{fetch_cached_earnings, EARNINGS_DEFAULT_MAX_AGE},
+        cast_embeds_handler::{
+            fetch_cached_embeds, CastEmbedsRequestQuery, EMBEDS_DEFAULT_MAX_AGE,
+        },
+        far_scores_handler::{fetch_cached_far_scores, FAR_SCORES_DEFAULT_MAX_AGE},
+        user_earnings_handler::{fetch_cached_user_earnings, USER_EARNINGS_DEFAULT_MAX_AGE},
+        AppState,
+    },
+    upstream::calls::count_calls,
+};
+
+// upper bound of distinct keys tracked per kind, the least requested ones are dropped
+const MAX_TRACKED: usize = 10_000;
+// share of the tracked keys dropped at once when the counter is full
+const EVICT_BATCH: usize = MAX_TRACKED / 10;
+
+struct Entry<V> {
+    value: V,
+    count: u64,
+    // decay round the key was first seen in
+    since: u64,
+}
+
+struct Entries<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    round: u64,
+    // set when nothing could be evicted, cleared on the next decay
+    full: bool,
+}
+
+struct Counter<K, V> {
+    entries: Mutex<Entries<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Counter<K, V> {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                entries: HashMap::new(),
+                round: 0,
+                full: false,
+            }),
+        }
+    }
+
+    // When full, the least requested keys are dropped in one batch so that eviction
+    // stays cheap. Keys first seen since the last decay are spared, which gives new
+    // keys the time to build up a count.
+    fn record(&self, key: K, value: V) {
+        let mut state = self.entries.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(&key) {
+            entry.count += 1;
+            return;
+        }
+        if state.entries.len() >= MAX_TRACKED {
+            if state.full {
+                return;
+            }
+            let round = state.round;
+            let mut evictable: Vec<(u64, K)> = state
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.since < round)
+                .map(|(k, entry)| (entry.count, k.clone()))
+                .collect();
+            if evictable.is_empty() {
+                state.full = true;
+                return;
+            }
+            let evict = EVICT_BATCH.min(evictable.len());
+            evictable.select_nth_unstable_by_key(evict - 1, |(count, _)| *count);
+            for (_, k) in evictable.into_iter().take(evict) {
+                state.entries.remove(&k);
+            }
+        }
+        let since = state.round;
+        state.entries.insert(
+            key,
+            Entry {
+                value,
+                count: 1,
+                since,
+            },
+        );
+    }
+
+    fn top(&self, n: usize) -> Vec<V> {
+        let state = self.entries.lock().unwrap();
+        let mut sorted: Vec<&Entry<V>> = state.entries.values().collect();
+        sorted.sort_by_key(|entry| std::cmp::Reverse(entry.count));
+        sorted
+            .into_iter()
+            .take(n)
+            .map(|entry| entry.value.clone())
+            .collect()
+    }
+
+    // halves all counts so that the ranking follows what is trending now
+    fn decay(&self) {
+        let mut state = self.entries.lock().unwrap();
+        state.round += 1;
+        state.full = false;
+        state.entries.retain(|_, entry| {
+            entry.count /= 2;
+            entry.count > 0
+        });
+    }
+}
+
+// Counts requests per cast and user so the warmer knows what to keep fresh
+pub struct RequestTracker {
+    casts: Counter<String, CastEmbedsRequestQuery>,
+    fids: Counter<u64, u64>,
+    handles: Counter<String, String>,
+}
+
+impl RequestTracker {
+    pub fn new() -> Self {
+        Self {
+            casts: Counter::new(),
+            fids: Counter::new(),
+            handles: Counter::new(),
+        }
+    }
+
+    pub fn record_cast(&self, params: &CastEmbedsRequestQuery) {
+        self.casts.record(params.cache_key(), params.clone());
+    }
+
+    pub fn record_fid(&self, fid: u64) {
+        self.fids.record(fid, fid);
+    }
+
+    pub fn record_handle(&self, handle: &str) {
+        self.handles.record(handle.to_string(), handle.to_string());
+    }
+
+    fn decay(&self) {
+        self.casts.decay();
+        self.fids.decay();
+        self.handles.decay();
+    }
+}
+
+struct Budget {
+    per_minute: u64,
+    used: u64,
+    window_start: Instant,
+}
+
+impl Budget {
+    fn new(per_minute: u64) -> Self {
+        Self {
+            per_minute,
+            used: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    fn exhausted(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(60) {
+            self.used = 0;
+            self.window_start = Instant::now();
+        }
+        self.used >= self.per_minute
+    }
+
+    // every upstream call of a refresh counts, refreshes served by the cache are free
+    fn charge(&mut self, calls: u64) {
+        self.used += calls;
+    }
+}
+
+// refresh a bit before the entry expires so that clients keep hitting the cache
+fn warm_max_age(default_max_age: u64, interval_secs: u64) -> u64 {
+    default_max_age.saturating_sub(interval_secs)
+}
+
+pub fn spawn(state: AppState) {
+    if state.config.cache_warmer_budget_per_minute == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let interval_secs = state.config.cache_warmer_interval_secs.max(1);
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        let mut budget = Budget::new(state.config.cache_warmer_budget_per_minute);
+        loop {
+            interval.tick().await;
+            warm(&state, &mut budget, interval_secs).await;
+            state.tracker.decay();
+        }
+    });
+}
+
+async fn warm(state: &AppState, budget: &mut Budget, interval_secs: u64) {
+    let config = &state.config;
+    let tracker = &state.tracker;
+    let top_n = config.cache_warmer_top_n;
+
+    for params in tracker.casts.top(top_n) {
+        if budget.exhausted() {
+            return;
+        }
+        let max_age = warm_max_age(EARNINGS_DEFAULT_MAX_AGE, interval_secs);
+        let (_, calls) = count_calls(fetch_cached_earnings(params.clone(), state, max_age)).await;
+        budget.charge(calls);
+
+        if budget.exhausted() {
+            return;
+        }
+        let max_age = warm_max_age(EMBEDS_DEFAULT_MAX_AGE, interval_secs);
+        let (_, calls) = count_calls(fetch_cached_embeds(params, state, max_age)).await;
+        budget.charge(calls);
+    }
+
+    for fid in tracker.fids.top(top_n) {
+        if budget.exhausted() {
+            return;
+        }
+        let max_age = warm_max_age(USER_EARNINGS_DEFAULT_MAX_AGE, interval_secs);
+        let (_, calls) = count_calls(fetch_cached_user_earnings(fid, config, max_age)).await;
+        budget.charge(calls);
+    }
+
+    for handle in tracker.handles.top(top_n) {
+        if budget.exhausted() {
+            return;
+        }
+        let max_age = warm_max_age(FAR_SCORES_DEFAULT_MAX_AGE, interval_secs);
+        let (_, calls) = count_calls(fetch_cached_far_scores(handle, state, max_age)).await;
+        budget.charge(calls);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(counter: &Counter<u64, u64>, keys: std::ops::Range<u64>) {
+        for key in keys {
+            counter.record(key, key);
+        }
+    }
+
+    #[test]
+    fn ranks_by_request_count() {
+        let counter = Counter::new();
+        for _ in 0..3 {
+            counter.record(1, 1);
+        }
+        counter.record(2, 2);
+        for _ in 0..2 {
+            counter.record(3, 3);
+        }
+        assert_eq!(counter.top(2), vec![1, 3]);
+    }
+
+    #[test]
+    fn decay_drops_keys_that_reach_zero() {
+        let counter = Counter::new();
+        counter.record(1, 1);
+        counter.record(2, 2);
+        counter.record(2, 2);
+        counter.decay();
+        assert_eq!(counter.top(10), vec![2]);
+    }
+
+    #[test]
+    fn eviction_spares_keys_seen_since_the_last_decay() {
+        let counter = Counter::new();
+        fill(&counter, 0..MAX_TRACKED as u64);
+        // nothing is older than the current round, so new keys aren't tracked
+        counter.record(u64::MAX, u64::MAX);
+        assert!(!counter.top(MAX_TRACKED).contains(&u64::MAX));
+
+        // after a decay, the old keys with the lowest counts make room
+        fill(&counter, 0..MAX_TRACKED as u64);
+        for _ in 0..4 {
+            counter.record(0, 0);
+        }
+        counter.decay();
+        let new_key = MAX_TRACKED as u64;
+        counter.record(new_key, new_key);
+        let tracked = counter.top(MAX_TRACKED);
+        assert!(tracked.contains(&new_key));
+        assert!(tracked.contains(&0));
+        assert_eq!(tracked.len(), MAX_TRACKED - EVICT_BATCH + 1);
+
+        // the new key is not the next one to go
+        for key in new_key + 1..new_key + EVICT_BATCH as u64 + 1 {
+            counter.record(key, key);
+        }
+        assert!(counter.top(MAX_TRACKED).contains(&new_key));
+    }
+}
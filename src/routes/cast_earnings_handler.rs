@@ -1,24 +1,30 @@
-use std::{fmt::Debug, sync::Arc};
+use std::fmt::Debug;
 
 use axum::{
     extract::{Query, State},
     http::{HeaderMap, StatusCode},
     Json,
 };
+use futures::future::join_all;
 use graphql_client::{GraphQLQuery, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::airstack::fetch_query;
+use crate::cache::{get_or_fetch, Freshness};
+use crate::providers::CastType;
 use crate::routes::{
-    cast_embeds_handler::{CastEmbedsRequestQuery, CastType},
-    config::Config,
-    fetch_cast_from_neynar::fetch_cast_from_neynar,
+    cast_embeds_handler::{resolve_cast_hash, CastEmbedsRequestQuery},
+    max_age::MaxAge,
+    AppState,
 };
 
+pub const EARNINGS_DEFAULT_MAX_AGE: u64 = 60;
+
 pub async fn get_cast_earnings(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
     headers: HeaderMap,
+    max_age: MaxAge,
     Query(params): Query<CastEmbedsRequestQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Check API key
@@ -26,16 +32,84 @@ pub async fn get_cast_earnings(
         .get("x-me-api-key")
         .and_then(|value| value.to_str().ok());
 
-    if api_key != Some(&config.api_key) {
+    if api_key != Some(&state.config.api_key) {
         return Err((
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Unauthorized"})),
         ));
     }
 
-    let earnings = fetch_earnings(params, &config).await?;
+    params.validate()?;
+    state.tracker.record_cast(&params);
+    let (earnings, freshness) =
+        fetch_cached_earnings(params, &state, max_age.or(EARNINGS_DEFAULT_MAX_AGE)).await?;
+
+    Ok(Json(json!({ "data": earnings, "meta": freshness })))
+}
 
-    Ok(Json(json!({ "data": earnings })))
+#[derive(Deserialize)]
+pub struct CastEarningsBatchRequest {
+    casts: Vec<CastEmbedsRequestQuery>,
+}
+
+// Earnings of many casts at once, one item per requested cast in request order. Failed
+// items carry their own error and status. Lookups by hash run concurrently and are
+// batched into shared Airstack queries.
+pub async fn get_cast_earnings_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+    Json(body): Json<CastEarningsBatchRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    if body.casts.len() > state.config.batch_max_items {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("At most {} casts per batch", state.config.batch_max_items)
+            })),
+        ));
+    }
+    if body
+        .casts
+        .iter()
+        .any(|params| params.cast_hash.is_none() && params.cast_url.is_none())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Each cast needs a castHash or castUrl"})),
+        ));
+    }
+
+    let max_age = max_age.or(EARNINGS_DEFAULT_MAX_AGE);
+    let results = join_all(body.casts.into_iter().map(|params| {
+        state.tracker.record_cast(&params);
+        fetch_cached_earnings(params, &state, max_age)
+    }))
+    .await;
+
+    let data: Vec<serde_json::Value> = results
+        .into_iter()
+        .map(|result| match result {
+            Ok((earnings, freshness)) => json!({ "data": earnings, "meta": freshness }),
+            Err((status, error)) => {
+                json!({ "error": error.0["error"], "status": status.as_u16() })
+            }
+        })
+        .collect();
+
+    Ok(Json(json!({ "data": data })))
 }
 
 #[derive(Deserialize, Debug)]
@@ -82,14 +156,14 @@ pub struct AirstackFarcasterCastMoxieEarningsSplit {
     earner_type: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct CastEarningsResponse {
     pub earnings: Earnings,
     pub creator: CreatorInfo,
     pub channel: Option<ChannelInfo>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct CreatorInfo {
     pub fid: i64,
@@ -97,14 +171,14 @@ pub struct CreatorInfo {
     pub profile_image: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ChannelInfo {
     pub name: String,
     pub image_url: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Earnings {
     pub channel_fans: f64,
@@ -125,25 +199,25 @@ impl Earnings {
         }
     }
 
-    fn add(&mut self, split: &AirstackFarcasterCastMoxieEarningsSplit) {
-        match split.earner_type.as_str() {
+    pub fn add(&mut self, earner_type: &str, earnings_amount: f64) {
+        match earner_type {
             "CHANNEL_FANS" => {
-                self.channel_fans += split.earnings_amount;
+                self.channel_fans += earnings_amount;
             }
             "CREATOR" => {
-                self.creator += split.earnings_amount;
+                self.creator += earnings_amount;
             }
             "NETWORK" => {
-                self.network += split.earnings_amount;
+                self.network += earnings_amount;
             }
             "CREATOR_FANS" => {
-                self.creator_fans += split.earnings_amount;
+                self.creator_fans += earnings_amount;
             }
             _ => {
-                println!("Unknown earner type: {}", split.earner_type);
+                println!("Unknown earner type: {}", earner_type);
             }
         }
-        self.total += split.earnings_amount;
+        self.total += earnings_amount;
     }
 }
 
@@ -157,13 +231,16 @@ fn extract_cast_earnings_response(
             data.farcaster_replies
                 .and_then(|fr| fr.reply.and_then(|replies| replies.into_iter().next()))
         })?;
+    Some(to_cast_earnings_response(earnings))
+}
 
-    Some(CastEarningsResponse {
+fn to_cast_earnings_response(earnings: AirstackFarcasterCastEarnings) -> CastEarningsResponse {
+    CastEarningsResponse {
         earnings: earnings
             .moxie_earnings_split
             .iter()
             .fold(Earnings::new(), |mut acc, split| {
-                acc.add(split);
+                acc.add(&split.earner_type, split.earnings_amount);
                 acc
             }),
         creator: CreatorInfo {
@@ -179,71 +256,37 @@ fn extract_cast_earnings_response(
             name: c.name,
             image_url: c.image_url,
         }),
-    })
+    }
+}
+
+pub async fn fetch_cached_earnings(
+    params: CastEmbedsRequestQuery,
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<CastEarningsResponse>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    let cache_key = format!("castEarnings/{}", params.cache_key());
+    get_or_fetch(&cache_key, max_age, || fetch_earnings(params, state)).await
 }
 
 async fn fetch_earnings(
     params: CastEmbedsRequestQuery,
-    config: &Config,
+    state: &AppState,
 ) -> Result<Option<CastEarningsResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let api_key = config.airstack_api_key.clone();
-    let cast_hash = match (
-        params.cast_type.clone(),
-        params.cast_hash.clone(),
-        params.cast_url.clone(),
-    ) {
-        (Some(CastType::Reply), None, Some(url)) | (None, None, Some(url)) => {
-            let cast_result = fetch_cast_from_neynar(&url).await;
-            match cast_result {
-                Ok(Some(cast)) => Some(cast.hash),
-                Ok(None) => {
-                    return Err((
-                        StatusCode::NOT_FOUND,
-                        Json(json!({"error": "Cast not found"})),
-                    ))
-                }
-                Err(e) => {
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({"error": e.to_string()})),
-                    ))
-                }
-            }
-        }
-        _ => params.cast_hash,
-    };
+    let cast_hash = resolve_cast_hash(&params, state).await?;
     let res = match (params.cast_type, cast_hash, params.cast_url) {
-        (Some(CastType::Cast), Some(hash), _) => {
-            let request_body =
-                CastEarningsByHashQuery::build_query(cast_earnings_by_hash_query::Variables {
-                    hash: hash.to_string(),
-                });
-            fetch_query::<_, Response<AirstackFarcasterCastEarningsDataResponse>>(
-                api_key,
-                &request_body,
-            )
-            .await
-        }
-        (Some(CastType::Reply), Some(hash), _) => {
-            let request_body =
-                ReplyEarningsByHashQuery::build_query(reply_earnings_by_hash_query::Variables {
-                    hash: hash.to_string(),
-                });
-            fetch_query::<_, Response<AirstackFarcasterCastEarningsDataResponse>>(
-                api_key,
-                &request_body,
-            )
-            .await
-        }
-        (None, Some(hash), _) => {
-            let request_body = CastAndReplyEarningsByHashQuery::build_query(
-                cast_and_reply_earnings_by_hash_query::Variables { hash: hash.clone() },
-            );
-            fetch_query::<_, Response<AirstackFarcasterCastEarningsDataResponse>>(
-                api_key,
-                &request_body,
-            )
-            .await
+        // lookups by hash are batched with concurrent requests for other casts
+        (cast_type, Some(hash), _) => {
+            let earnings = state
+                .casts
+                .load(&hash)
+                .await
+                .and_then(|batched| {
+                    batched
+                        .unwrap_or_default()
+                        .select::<AirstackFarcasterCastEarnings>(cast_type.as_ref())
+                })
+                .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+            return Ok(earnings.map(to_cast_earnings_response));
         }
         (Some(CastType::Cast), None, Some(url)) => {
             let request_body =
@@ -251,7 +294,7 @@ async fn fetch_earnings(
                     url: url.clone(),
                 });
             fetch_query::<_, Response<AirstackFarcasterCastEarningsDataResponse>>(
-                api_key,
+                &state.config,
                 &request_body,
             )
             .await
@@ -265,33 +308,12 @@ async fn fetch_earnings(
     };
 
     let earnings_result = res
-        .map(|r| r.data.and_then(|data| extract_cast_earnings_response(data)))
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": e.to_string()})),
-            )
-        })?;
+        .map(|r| r.data.and_then(extract_cast_earnings_response))
+        .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
 
-    return Ok(earnings_result);
+    Ok(earnings_result)
 }
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_earnings_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct CastEarningsByHashQuery;
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_earnings_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct ReplyEarningsByHashQuery;
-
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "src/gql/airstack_schema.graphql",
@@ -299,11 +321,3 @@ pub struct ReplyEarningsByHashQuery;
     response_derives = "Debug"
 )]
 pub struct CastEarningsByUrlQuery;
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_earnings_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct CastAndReplyEarningsByHashQuery;
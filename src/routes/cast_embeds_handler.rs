@@ -1,38 +1,65 @@
-use std::{fmt::Debug, sync::Arc};
-
 use axum::{
     extract::{Query, State},
     http::{HeaderMap, StatusCode},
     Json,
 };
-use graphql_client::{GraphQLQuery, Response};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde::Deserialize;
+use serde_json::json;
 
 use crate::{
-    airstack::fetch_query,
-    routes::{config::Config, fetch_cast_from_neynar::fetch_cast_from_neynar},
+    cache::{get_or_fetch, Freshness},
+    providers::{CastRef, CastType, Embed, Provided},
+    routes::{max_age::MaxAge, AppState, ResponseMeta},
 };
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum CastType {
-    Cast,
-    Reply,
-}
+// embeds never change once a cast is published
+pub const EMBEDS_DEFAULT_MAX_AGE: u64 = 24 * 60 * 60;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CastEmbedsRequestQuery {
     pub cast_hash: Option<String>,
     pub cast_url: Option<String>,
     #[serde(rename = "type")]
     pub cast_type: Option<CastType>,
+    pub fid: Option<u64>,
+}
+
+impl CastEmbedsRequestQuery {
+    pub fn cache_key(&self) -> String {
+        let cast_type = match self.cast_type {
+            Some(CastType::Cast) => "cast",
+            Some(CastType::Reply) => "reply",
+            None => "any",
+        };
+        let cast = match (&self.cast_hash, &self.cast_url) {
+            (Some(hash), _) => format!("{}/hash/{}", cast_type, hash),
+            (None, Some(url)) => format!("{}/url/{}", cast_type, url),
+            (None, None) => format!("{}/none", cast_type),
+        };
+        // hubs look casts up by author, a wrong fid finds nothing
+        match self.fid {
+            Some(fid) => format!("{}/fid/{}", cast, fid),
+            None => cast,
+        }
+    }
+
+    // A cast needs a hash or URL, checked before the request is tracked for warming
+    pub fn validate(&self) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+        if self.cast_hash.is_none() && self.cast_url.is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid parameters"})),
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub async fn get_cast_embeds(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
     headers: HeaderMap,
+    max_age: MaxAge,
     Query(params): Query<CastEmbedsRequestQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Check API key
@@ -40,186 +67,83 @@ pub async fn get_cast_embeds(
         .get("x-me-api-key")
         .and_then(|value| value.to_str().ok());
 
-    if api_key != Some(&config.api_key) {
+    if api_key != Some(&state.config.api_key) {
         return Err((
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Unauthorized"})),
         ));
     }
 
-    let embeds = fetch_embeds(params, &config).await?;
+    params.validate()?;
+    state.tracker.record_cast(&params);
+    let (embeds, freshness) =
+        fetch_cached_embeds(params, &state, max_age.or(EMBEDS_DEFAULT_MAX_AGE)).await?;
 
-    Ok(Json(
-        json!({ "data": embeds.map(|e| json!({ "embeds": e })).or(None) }),
-    ))
-}
+    let meta = ResponseMeta {
+        freshness,
+        provider: embeds.as_ref().map(|e| e.provider.clone()),
+    };
 
-#[derive(Serialize, Deserialize)]
-pub struct Embed {
-    pub url: Option<String>,
+    Ok(Json(json!({
+        "data": embeds.map(|e| json!({ "embeds": e.data })).or(None),
+        "meta": meta,
+    })))
 }
 
-async fn fetch_embeds(
+pub async fn fetch_cached_embeds(
     params: CastEmbedsRequestQuery,
-    config: &Config,
-) -> Result<Option<Vec<Embed>>, (StatusCode, Json<serde_json::Value>)> {
-    let api_key = config.airstack_api_key.clone();
-    let cast_hash = match (
-        params.cast_type.clone(),
-        params.cast_hash.clone(),
-        params.cast_url.clone(),
-    ) {
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<Provided<Vec<Embed>>>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    // bumped to v2 when the provider was added to the cached embeds
+    let cache_key = format!("castEmbeds/v2/{}", params.cache_key());
+    get_or_fetch(&cache_key, max_age, || fetch_embeds(params, state)).await
+}
+
+// Resolves cast URLs that can't be looked up directly (replies and casts of unknown type)
+// to a hash
+pub async fn resolve_cast_hash(
+    params: &CastEmbedsRequestQuery,
+    state: &AppState,
+) -> Result<Option<String>, (StatusCode, Json<serde_json::Value>)> {
+    match (&params.cast_type, &params.cast_hash, &params.cast_url) {
         (Some(CastType::Reply), None, Some(url)) | (None, None, Some(url)) => {
-            let cast_result = fetch_cast_from_neynar(&url).await;
+            let cast_result = state.providers.resolve_cast(url).await;
             match cast_result {
-                Ok(Some(cast)) => Some(cast.hash),
-                Ok(None) => {
-                    return Err((
-                        StatusCode::NOT_FOUND,
-                        Json(json!({"error": "Cast not found"})),
-                    ))
-                }
-                Err(e) => {
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({"error": e.to_string()})),
-                    ))
-                }
+                Ok(Some(resolved)) => Ok(Some(resolved.data)),
+                Ok(None) => Err((
+                    StatusCode::NOT_FOUND,
+                    Json(json!({"error": "Cast not found"})),
+                )),
+                Err(e) => Err((e.status_code(), Json(json!({"error": e.to_string()})))),
             }
         }
-        _ => params.cast_hash,
-    };
-    let embeds_result = match (params.cast_type, cast_hash, params.cast_url) {
-        (Some(CastType::Cast), Some(hash), _) => {
-            let request_body =
-                CastEmbedsByHashQuery::build_query(cast_embeds_by_hash_query::Variables {
-                    hash: hash.to_string(),
-                });
-            let res = fetch_query::<_, Response<cast_embeds_by_hash_query::ResponseData>>(
-                api_key,
-                &request_body,
-            )
-            .await;
-            res.map(|r| {
-                r.data
-                    .and_then(|d| d.farcaster_casts.cast.first().map(|c| c.embeds.clone()))
-                    .or(None)
-            })
-        }
-        (Some(CastType::Reply), Some(hash), _) => {
-            let request_body =
-                ReplyEmbedsByHashQuery::build_query(reply_embeds_by_hash_query::Variables {
-                    hash: hash.to_string(),
-                });
-            let res = fetch_query::<_, Response<reply_embeds_by_hash_query::ResponseData>>(
-                api_key,
-                &request_body,
-            )
-            .await;
-            res.map(|r| {
-                r.data
-                    .and_then(|d| d.farcaster_replies.reply.first().map(|c| c.embeds.clone()))
-                    .or(None)
-            })
-        }
-        (None, Some(hash), _) => {
-            let request_body = CastAndReplyEmbedsByHashQuery::build_query(
-                cast_and_reply_embeds_by_hash_query::Variables { hash: hash.clone() },
-            );
-            let res =
-                fetch_query::<_, Response<cast_and_reply_embeds_by_hash_query::ResponseData>>(
-                    api_key,
-                    &request_body,
-                )
-                .await;
-            res.map(|r| {
-                r.data
-                    .and_then(|d| {
-                        d.farcaster_casts
-                            .cast
-                            .first()
-                            .map(|c| c.embeds.clone())
-                            .or_else(|| d.farcaster_replies.reply.first().map(|r| r.embeds.clone()))
-                    })
-                    .or(None)
-            })
-        }
-        (Some(CastType::Cast), None, Some(url)) => {
-            let request_body =
-                CastEmbedsByUrlQuery::build_query(cast_embeds_by_url_query::Variables {
-                    url: url.clone(),
-                });
-            let res = fetch_query::<_, Response<cast_embeds_by_url_query::ResponseData>>(
-                api_key,
-                &request_body,
-            )
-            .await;
-            res.map(|r| {
-                r.data
-                    .and_then(|d| d.farcaster_casts.cast.first().map(|c| c.embeds.clone()))
-                    .or(None)
-            })
-        }
-        _ => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "Invalid parameters"})),
-            ))
-        }
+        _ => Ok(params.cast_hash.clone()),
+    }
+}
+
+async fn fetch_embeds(
+    params: CastEmbedsRequestQuery,
+    state: &AppState,
+) -> Result<Option<Provided<Vec<Embed>>>, (StatusCode, Json<serde_json::Value>)> {
+    let cast_hash = resolve_cast_hash(&params, state).await?;
+    let cast = CastRef {
+        hash: cast_hash,
+        url: params.cast_url,
+        cast_type: params.cast_type,
+        fid: params.fid,
     };
 
-    let embeds = embeds_result.map(|embeds_optional| {
-        embeds_optional.map(|embeds| {
-            embeds
-                .iter()
-                .map(|embed| match embed["url"].as_str() {
-                    Some(url) => Embed {
-                        url: Some(url.to_string()),
-                    },
-                    _ => Embed { url: None },
-                })
-                .collect::<Vec<Embed>>()
+    state
+        .providers
+        .get_embeds(&cast)
+        .await
+        .map_err(|e| match e.status_code() {
+            // connection errors are not passed on to clients
+            StatusCode::INTERNAL_SERVER_ERROR => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal server error"})),
+            ),
+            status => (status, Json(json!({"error": e.to_string()}))),
         })
-    });
-
-    return embeds.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "Internal server error"})),
-        )
-    });
 }
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_embeds_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct CastEmbedsByHashQuery;
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_embeds_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct ReplyEmbedsByHashQuery;
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_embeds_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct CastEmbedsByUrlQuery;
-
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/cast_embeds_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct CastAndReplyEmbedsByHashQuery;
-
-type Map = Value;
@@ -0,0 +1,109 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    cache::{get_or_fetch, Freshness},
+    providers::{Cast, CastRef, Provided},
+    routes::{max_age::MaxAge, AppState, ResponseMeta},
+};
+
+// reaction counts keep changing, the rest of a cast never does
+pub const CAST_DEFAULT_MAX_AGE: u64 = 5 * 60;
+
+// Handler for GET /casts/:hash_or_url, URLs have to be percent-encoded
+pub async fn get_cast(
+    State(state): State<AppState>,
+    Path(hash_or_url): Path<String>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let cast = to_cast_ref(&hash_or_url).ok_or((
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": format!("Invalid cast identifier: {}", hash_or_url)})),
+    ))?;
+
+    let (cast, freshness) =
+        fetch_cached_cast(cast, &state, max_age.or(CAST_DEFAULT_MAX_AGE)).await?;
+    let meta = ResponseMeta {
+        freshness,
+        provider: cast.as_ref().map(|c| c.provider.clone()),
+    };
+
+    Ok(Json(json!({ "data": cast.map(|c| c.data), "meta": meta })))
+}
+
+fn to_cast_ref(hash_or_url: &str) -> Option<CastRef> {
+    let (hash, url) = if hash_or_url.starts_with("https://") {
+        (None, Some(hash_or_url.to_string()))
+    } else {
+        let hex = hash_or_url.strip_prefix("0x")?;
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        (Some(hash_or_url.to_lowercase()), None)
+    };
+    Some(CastRef {
+        hash,
+        url,
+        cast_type: None,
+        fid: None,
+    })
+}
+
+pub async fn fetch_cached_cast(
+    cast: CastRef,
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<Provided<Cast>>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    let cache_key = match (&cast.hash, &cast.url) {
+        (Some(hash), _) => format!("cast/hash/{}", hash),
+        (None, Some(url)) => format!("cast/url/{}", url),
+        (None, None) => "cast/none".to_string(),
+    };
+    get_or_fetch(&cache_key, max_age, || async {
+        state
+            .providers
+            .get_cast(&cast)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_hashes_and_urls() {
+        let url = "https://warpcast.com/dwr/0xa1b2c3d4";
+        let by_url = to_cast_ref(url).unwrap();
+        assert_eq!(by_url.url.as_deref(), Some(url));
+        assert!(by_url.hash.is_none());
+
+        let by_hash = to_cast_ref("0xA1B2c3D4").unwrap();
+        assert_eq!(by_hash.hash.as_deref(), Some("0xa1b2c3d4"));
+        assert!(by_hash.url.is_none());
+
+        assert!(to_cast_ref("0xzz").is_none());
+        assert!(to_cast_ref("0x").is_none());
+        assert!(to_cast_ref("a1b2c3d4").is_none());
+        assert!(to_cast_ref("http://warpcast.com/dwr/0xa1b2c3d4").is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use graphql_client::{GraphQLQuery, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::airstack::fetch_query;
+use crate::cache::{get_or_fetch, Freshness};
+use crate::providers::{airstack::to_embeds, CastType, Embed};
+use crate::routes::{
+    cast_earnings_handler::{ChannelInfo, CreatorInfo, Earnings, EARNINGS_DEFAULT_MAX_AGE},
+    cast_embeds_handler::{resolve_cast_hash, CastEmbedsRequestQuery},
+    max_age::MaxAge,
+    AppState,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CastOverviewResponse {
+    pub hash: String,
+    pub embeds: Vec<Embed>,
+    pub earnings: Earnings,
+    pub creator: CreatorInfo,
+    pub channel: Option<ChannelInfo>,
+}
+
+// Handler for GET /casts/overview, embeds and earnings of a cast in one response
+pub async fn get_cast_overview(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+    Query(params): Query<CastEmbedsRequestQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    params.validate()?;
+    state.tracker.record_cast(&params);
+    let (overview, freshness) =
+        fetch_cached_overview(params, &state, max_age.or(EARNINGS_DEFAULT_MAX_AGE)).await?;
+
+    Ok(Json(json!({ "data": overview, "meta": freshness })))
+}
+
+pub async fn fetch_cached_overview(
+    params: CastEmbedsRequestQuery,
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<CastOverviewResponse>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    let cache_key = format!("castOverview/{}", params.cache_key());
+    get_or_fetch(&cache_key, max_age, || fetch_overview(params, state)).await
+}
+
+// Both lookups select the same fragment, so batched casts decode as overviews too
+type OverviewCast = cast_overview_by_url_query::CastOverviewFragment;
+
+fn to_overview(cast: OverviewCast) -> CastOverviewResponse {
+    CastOverviewResponse {
+        hash: cast.hash,
+        embeds: to_embeds(&cast.embeds),
+        earnings: cast
+            .moxie_earnings_split
+            .iter()
+            .fold(Earnings::new(), |mut acc, split| {
+                acc.add(&split.earner_type, split.earnings_amount);
+                acc
+            }),
+        creator: CreatorInfo {
+            fid: cast.casted_by.user_id.parse::<i64>().unwrap_or_default(),
+            username: cast.casted_by.fnames.into_iter().next(),
+            profile_image: cast.casted_by.profile_image,
+        },
+        channel: cast.channel.map(|c| ChannelInfo {
+            name: c.name,
+            image_url: c.image_url,
+        }),
+    }
+}
+
+// The cast URL is resolved at most once, then a single Airstack query returns the
+// embeds, earnings split, creator and channel together
+async fn fetch_overview(
+    params: CastEmbedsRequestQuery,
+    state: &AppState,
+) -> Result<Option<CastOverviewResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let cast_hash = resolve_cast_hash(&params, state).await?;
+    let node = match (params.cast_type, cast_hash, params.cast_url) {
+        // lookups by hash are batched with concurrent requests for other casts
+        (cast_type, Some(hash), _) => state
+            .casts
+            .load(&hash)
+            .await
+            .and_then(|batched| {
+                batched
+                    .unwrap_or_default()
+                    .select::<OverviewCast>(cast_type.as_ref())
+            })
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?,
+        (Some(CastType::Cast), None, Some(url)) => {
+            let request_body =
+                CastOverviewByUrlQuery::build_query(cast_overview_by_url_query::Variables { url });
+            let res = fetch_query::<_, Response<cast_overview_by_url_query::ResponseData>>(
+                &state.config,
+                &request_body,
+            )
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+            res.data
+                .and_then(|d| d.farcaster_casts.cast.into_iter().next())
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid parameters"})),
+            ))
+        }
+    };
+
+    Ok(node.map(to_overview))
+}
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/cast_batch_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct CastOverviewByUrlQuery;
+
+type Map = Value;
@@ -0,0 +1,120 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use graphql_client::{GraphQLQuery, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::airstack::fetch_query;
+use crate::cache::{get_or_fetch, Freshness};
+use crate::providers::Provided;
+use crate::routes::{
+    config::Config,
+    max_age::MaxAge,
+    user_earnings_handler::{
+        from_airstack, moxie_earnings_query, to_airstack_earning_stat, AirstackEarningStat,
+        MoxieEarningsQuery,
+    },
+    AppState, ResponseMeta,
+};
+
+use moxie_earnings_query::EntityType;
+
+// a channel's totals move slower than a single user's
+pub const CHANNEL_EARNINGS_DEFAULT_MAX_AGE: u64 = 5 * 60;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelEarnings {
+    today: Option<AirstackEarningStat>,
+    weekly: Option<AirstackEarningStat>,
+    lifetime: Option<AirstackEarningStat>,
+}
+
+// Handler for GET /channels/:channel_id/earnings
+pub async fn get_channel_earnings(
+    State(state): State<AppState>,
+    Path(channel_id): Path<String>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    // channel ids are lowercase letters, digits and dashes
+    let channel_id = channel_id.to_lowercase();
+    if channel_id.is_empty()
+        || !channel_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Invalid channel identifier: {}", channel_id)})),
+        ));
+    }
+
+    let (earnings, freshness) = fetch_cached_channel_earnings(
+        channel_id,
+        &state.config,
+        max_age.or(CHANNEL_EARNINGS_DEFAULT_MAX_AGE),
+    )
+    .await?;
+    let meta = ResponseMeta {
+        freshness,
+        provider: earnings.as_ref().map(|e| e.provider.clone()),
+    };
+
+    Ok(Json(
+        json!({"data": earnings.map(|e| e.data), "meta": meta}),
+    ))
+}
+
+// `channel_id` as in the channel's URL, e.g. `moxie` for /channel/moxie. v2 entries
+// hold the earnings along with the provider that served them.
+pub async fn fetch_cached_channel_earnings(
+    channel_id: String,
+    config: &Config,
+    max_age: u64,
+) -> Result<(Option<Provided<ChannelEarnings>>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    let cache_key = format!("channelEarnings/v2/{}", channel_id);
+    get_or_fetch(&cache_key, max_age, || async {
+        let earnings = fetch_channel_earnings(channel_id, config).await?;
+        Ok(earnings.map(from_airstack))
+    })
+    .await
+}
+
+async fn fetch_channel_earnings(
+    channel_id: String,
+    config: &Config,
+) -> Result<Option<ChannelEarnings>, (StatusCode, Json<serde_json::Value>)> {
+    let request_body = MoxieEarningsQuery::build_query(moxie_earnings_query::Variables {
+        entity_type: EntityType::CHANNEL,
+        entity_id: channel_id,
+    });
+    let response_body =
+        fetch_query::<_, Response<moxie_earnings_query::ResponseData>>(config, &request_body)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+    // without any stats there are no earnings, as for users
+    Ok(response_body
+        .data
+        .map(|d| ChannelEarnings {
+            today: to_airstack_earning_stat(d.today.farcaster_moxie_earning_stat.first()),
+            weekly: to_airstack_earning_stat(d.weekly.farcaster_moxie_earning_stat.first()),
+            lifetime: to_airstack_earning_stat(d.lifetime.farcaster_moxie_earning_stat.first()),
+        })
+        .filter(|e| e.today.is_some() || e.weekly.is_some() || e.lifetime.is_some()))
+}
@@ -1,4 +1,147 @@
+use std::env;
+
+use crate::airstack::AIRSTACK_PAGE_SIZE;
+use crate::cache::RedisMode;
+use crate::providers::ProviderRouting;
+use crate::routes::deadline::{parse_route_deadlines, Deadlines};
+use crate::upstream::{
+    fixtures::{FixtureMode, Fixtures},
+    rate_limit::UpstreamLimits,
+};
+
+#[derive(Debug, Clone)]
+pub struct AirstackApiKey {
+    pub key: String,
+    pub weight: u32,
+}
+
 pub struct Config {
     pub api_key: String,
-    pub airstack_api_key: String,
+    // AIRSTACK_API_KEYS=key1:3,key2 weighs key1 three times as much as key2
+    pub airstack_api_keys: Vec<AirstackApiKey>,
+    // how long a rate limited key is skipped when Airstack sends no Retry-After
+    pub airstack_key_park_secs: u64,
+    pub airstack_api_url: String,
+    pub neynar_api_key: Option<String>,
+    pub neynar_api_url: String,
+    pub warpcast_api_url: String,
+    // self-hosted hubs, tried in order
+    pub hub_api_urls: Vec<String>,
+    pub airstack_limits: UpstreamLimits,
+    // cast lookups made within the window are sent as one query
+    pub airstack_batch_window_ms: u64,
+    pub airstack_batch_size: usize,
+    // a second request is sent when Airstack hasn't answered after this long, 0 disables it
+    pub airstack_hedge_delay_ms: u64,
+    // share of requests (in percent) that may be hedged
+    pub airstack_hedge_budget_percent: u64,
+    pub neynar_limits: UpstreamLimits,
+    pub providers: ProviderRouting,
+    pub fixtures: Fixtures,
+    pub deadlines: Deadlines,
+    // most items a batch endpoint accepts per request
+    pub batch_max_items: usize,
+    // upstream user lookups a batch resolution runs at once
+    pub resolve_concurrency: usize,
+    // upstream calls the cache warmer may spend per minute, 0 disables it
+    pub cache_warmer_budget_per_minute: u64,
+    pub cache_warmer_interval_secs: u64,
+    pub cache_warmer_top_n: usize,
+    // parsed with the rest so that an unknown REDIS_MODE fails at startup
+    pub redis_mode: RedisMode,
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// AIRSTACK_API_KEYS, falling back to the single AIRSTACK_API_KEY. Replayed fixtures
+// need no key, so a placeholder is used there.
+fn airstack_api_keys(replay: bool) -> Vec<AirstackApiKey> {
+    let keys: Vec<AirstackApiKey> = env::var("AIRSTACK_API_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(|k| match k.rsplit_once(':') {
+            Some((key, weight)) if weight.parse::<u32>().is_ok() => AirstackApiKey {
+                key: key.to_string(),
+                weight: weight.parse().unwrap(),
+            },
+            _ => AirstackApiKey {
+                key: k.to_string(),
+                weight: 1,
+            },
+        })
+        .collect();
+    if !keys.is_empty() {
+        return keys;
+    }
+    let key = match env::var("AIRSTACK_API_KEY") {
+        Ok(key) => key,
+        Err(_) if replay => REPLAY_API_KEY.to_string(),
+        Err(_) => panic!("AIRSTACK_API_KEY must be set"),
+    };
+    vec![AirstackApiKey { key, weight: 1 }]
+}
+
+// stands in for API keys that aren't set when replaying fixtures, which don't need them
+const REPLAY_API_KEY: &str = "replay";
+
+impl Config {
+    pub fn from_env() -> Self {
+        let fixtures = Fixtures::from_env();
+        let replay = fixtures.mode == FixtureMode::Replay;
+        Self {
+            api_key: match env::var("API_KEY") {
+                Ok(key) => key,
+                Err(_) if replay => REPLAY_API_KEY.to_string(),
+                Err(_) => panic!("API_KEY must be set"),
+            },
+            airstack_api_keys: airstack_api_keys(replay),
+            airstack_key_park_secs: env_or("AIRSTACK_KEY_PARK_SECS", 60),
+            airstack_api_url: env::var("AIRSTACK_API_URL")
+                .unwrap_or("https://api.airstack.xyz/gql".to_string()),
+            // without a key the Neynar client is skipped before it could replay anything
+            neynar_api_key: env::var("NEYNAR_API_KEY")
+                .ok()
+                .or_else(|| replay.then(|| REPLAY_API_KEY.to_string())),
+            neynar_api_url: env::var("NEYNAR_API_URL")
+                .unwrap_or("https://api.neynar.com/v2/farcaster".to_string()),
+            warpcast_api_url: env::var("WARPCAST_API_URL")
+                .unwrap_or("https://api.warpcast.com/v2".to_string()),
+            hub_api_urls: env::var("HUB_API_URLS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            airstack_limits: UpstreamLimits::from_env("AIRSTACK"),
+            airstack_batch_window_ms: env_or("AIRSTACK_BATCH_WINDOW_MS", 20),
+            // batches depend on request timing, which would make fixture keys vary
+            airstack_batch_size: match fixtures.mode {
+                FixtureMode::Off => env_or("AIRSTACK_BATCH_SIZE", 50).clamp(1, AIRSTACK_PAGE_SIZE),
+                _ => 1,
+            },
+            airstack_hedge_delay_ms: env_or("AIRSTACK_HEDGE_DELAY_MS", 0),
+            airstack_hedge_budget_percent: env_or("AIRSTACK_HEDGE_BUDGET_PERCENT", 10),
+            neynar_limits: UpstreamLimits::from_env("NEYNAR"),
+            providers: ProviderRouting::from_env(),
+            fixtures,
+            batch_max_items: env_or("BATCH_MAX_ITEMS", 50),
+            resolve_concurrency: env_or("RESOLVE_CONCURRENCY", 8).max(1),
+            deadlines: Deadlines {
+                default_ms: env_or("REQUEST_DEADLINE_MS", 10_000),
+                max_ms: env_or("REQUEST_DEADLINE_MAX_MS", 30_000),
+                routes: parse_route_deadlines(&env::var("REQUEST_DEADLINES").unwrap_or_default()),
+            },
+            cache_warmer_budget_per_minute: env_or("CACHE_WARMER_BUDGET_PER_MINUTE", 0),
+            cache_warmer_interval_secs: env_or("CACHE_WARMER_INTERVAL_SECS", 30),
+            cache_warmer_top_n: env_or("CACHE_WARMER_TOP_N", 50),
+            redis_mode: RedisMode::from_env(),
+        }
+    }
 }
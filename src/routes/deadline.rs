@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tokio::time::Instant;
+
+use crate::routes::config::Config;
+use crate::upstream::deadline::with_deadline;
+
+// lets clients ask for a shorter or longer deadline, capped at the configured maximum
+const DEADLINE_HEADER: &str = "x-request-timeout-ms";
+
+#[derive(Debug, Clone)]
+pub struct Deadlines {
+    pub default_ms: u64,
+    pub max_ms: u64,
+    // per route, keyed by the route path, e.g. `/api/v1/earnings`
+    pub routes: HashMap<String, u64>,
+}
+
+impl Deadlines {
+    fn for_route(&self, path: Option<&str>) -> u64 {
+        path.and_then(|p| self.routes.get(p))
+            .copied()
+            .unwrap_or(self.default_ms)
+    }
+}
+
+// Parses REQUEST_DEADLINES, e.g. `/api/v1/earnings=3000,/api/v1/casts/embeds=5000`
+pub fn parse_route_deadlines(value: &str) -> HashMap<String, u64> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (path, ms) = entry.split_once('=')?;
+            Some((path.trim().to_string(), ms.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+// Fails the request with 504 once its deadline passes. Upstream calls made while
+// handling it only get the time that's left.
+pub async fn enforce_deadline(
+    State(config): State<Arc<Config>>,
+    path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let deadlines = &config.deadlines;
+    let requested = request
+        .headers()
+        .get(DEADLINE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let timeout_ms = requested
+        .unwrap_or_else(|| deadlines.for_route(path.as_ref().map(|p| p.as_str())))
+        .min(deadlines.max_ms);
+
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    match tokio::time::timeout_at(deadline, with_deadline(deadline, next.run(request))).await {
+        Ok(response) => response,
+        Err(_) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(json!({"error": "Request timed out"})),
+        )
+            .into_response(),
+    }
+}
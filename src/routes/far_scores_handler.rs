@@ -1,24 +1,30 @@
-use std::sync::Arc;
+use std::collections::HashMap;
 
 use axum::{
-    extract::{Query, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
     Json,
 };
-use graphql_client::{GraphQLQuery, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::routes::config::Config;
+use crate::cache::{get_or_fetch, get_or_fetch_many, Freshness};
+use crate::providers::{Provided, SocialScore};
+use crate::routes::{max_age::MaxAge, AppState, ResponseMeta};
+use crate::warpcast::normalize_handle;
+
+pub const FAR_SCORES_DEFAULT_MAX_AGE: u64 = 60 * 60;
 
 #[derive(Deserialize)]
 pub struct FarScoreQuery {
     handle: Option<String>,
+    fid: Option<u64>,
 }
 
 pub async fn get_far_scores(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
     headers: HeaderMap,
+    max_age: MaxAge,
     Query(params): Query<FarScoreQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Check API key
@@ -26,83 +32,291 @@ pub async fn get_far_scores(
         .get("x-me-api-key")
         .and_then(|value| value.to_str().ok());
 
-    if api_key != Some(&config.api_key) {
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let max_age = max_age.or(FAR_SCORES_DEFAULT_MAX_AGE);
+    let far_scores = match (params.fid, params.handle) {
+        // the fid wins, handles change when users rename
+        (Some(fid), _) => fetch_cached_far_scores_by_fid(fid, &state, max_age).await,
+        (None, Some(handle)) => {
+            let handle = normalize_handle(&handle).ok_or((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid handle"})),
+            ))?;
+            state.tracker.record_handle(&handle);
+            fetch_cached_far_scores(handle, &state, max_age).await
+        }
+        (None, None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Handle or fid is required"})),
+            ))
+        }
+    };
+
+    let (far_stats, freshness) = far_scores?;
+    Ok(far_scores_response(far_stats, freshness))
+}
+
+// Handler for GET /users/:fid/far-score
+pub async fn get_user_far_score(
+    State(state): State<AppState>,
+    Path(fid): Path<String>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    // Parse and validate fid
+    let fid: u64 = fid.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Invalid user identifier: {}", fid)})),
+        )
+    })?;
+
+    let (far_stats, freshness) =
+        fetch_cached_far_scores_by_fid(fid, &state, max_age.or(FAR_SCORES_DEFAULT_MAX_AGE)).await?;
+    Ok(far_scores_response(far_stats, freshness))
+}
+
+fn far_scores_response(
+    far_stats: Option<Provided<FarStatsResponse>>,
+    freshness: Freshness,
+) -> Json<serde_json::Value> {
+    let meta = ResponseMeta {
+        freshness,
+        provider: far_stats.as_ref().map(|s| s.provider.clone()),
+    };
+    Json(json!({"data": far_stats.map(|s| s.data), "meta": meta}))
+}
+
+#[derive(Deserialize)]
+pub struct FarScoresBatchRequest {
+    #[serde(default)]
+    handles: Vec<String>,
+    #[serde(default)]
+    fids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ScoreKey {
+    Handle(String),
+    Fid(u64),
+}
+
+impl ScoreKey {
+    // v2 entries hold the score along with the provider that served it
+    fn cache_key(&self) -> String {
+        match self {
+            ScoreKey::Handle(handle) => format!("farScores/v2/{}", handle),
+            ScoreKey::Fid(fid) => format!("farScores/v2/fid/{}", fid),
+        }
+    }
+}
+
+// Handler for POST /far-scores/batch, scores keyed by the handles and FIDs as sent.
+// Everything missing from the cache is fetched with one batched lookup.
+pub async fn get_far_scores_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+    Json(body): Json<FarScoresBatchRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
         return Err((
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Unauthorized"})),
         ));
     }
 
-    if params.handle.is_none() {
+    let mut keys: Vec<ScoreKey> = Vec::new();
+    let mut handles = serde_json::Map::new();
+    // "@Dwr" and "dwr" share a lookup but each gets its own result
+    let mut sent_handles: HashMap<String, Vec<String>> = HashMap::new();
+    for sent in &body.handles {
+        match normalize_handle(sent) {
+            Some(handle) => {
+                sent_handles
+                    .entry(handle.clone())
+                    .or_default()
+                    .push(sent.clone());
+                keys.push(ScoreKey::Handle(handle));
+            }
+            None => {
+                handles.insert(sent.clone(), json!({"error": "Invalid handle"}));
+            }
+        }
+    }
+    keys.extend(body.fids.iter().map(|fid| ScoreKey::Fid(*fid)));
+    keys.sort_by_key(|k| k.cache_key());
+    keys.dedup();
+    if keys.len() > state.config.batch_max_items {
         return Err((
             StatusCode::BAD_REQUEST,
-            Json(json!({"error": "Handle is required"})),
+            Json(json!({
+                "error": format!("At most {} users per batch", state.config.batch_max_items)
+            })),
         ));
     }
 
-    let far_scores = fetch_far_scores(params.handle.unwrap(), &config).await;
+    for key in &keys {
+        if let ScoreKey::Handle(handle) = key {
+            state.tracker.record_handle(handle);
+        }
+    }
+    let entries = keys.iter().map(|k| (k.clone(), k.cache_key())).collect();
+    let results = get_or_fetch_many(entries, max_age.or(FAR_SCORES_DEFAULT_MAX_AGE), |missing| {
+        fetch_far_scores_many(missing, &state)
+    })
+    .await;
 
-    match far_scores {
-        Ok(far_stats) => Ok(Json(json!({"data": far_stats}))),
-        Err(e) => Err(e),
+    let mut fids = serde_json::Map::new();
+    for (key, result) in keys.into_iter().zip(results) {
+        let item = match result {
+            Ok((far_stats, freshness)) => {
+                let meta = ResponseMeta {
+                    freshness,
+                    provider: far_stats.as_ref().map(|s| s.provider.clone()),
+                };
+                json!({"data": far_stats.map(|s| s.data), "meta": meta})
+            }
+            Err((_, error)) => error.0,
+        };
+        match key {
+            ScoreKey::Handle(handle) => {
+                for sent in sent_handles.remove(&handle).unwrap_or_default() {
+                    handles.insert(sent, item.clone());
+                }
+            }
+            ScoreKey::Fid(fid) => {
+                fids.insert(fid.to_string(), item);
+            }
+        }
     }
-    // Ok(Json(json!({"data": 1 })))
+
+    Ok(Json(
+        json!({ "data": { "handles": handles, "fids": fids } }),
+    ))
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct FarStatsResponse {
+pub struct FarStatsResponse {
     far_score: f64,
     far_rank: i64,
+    // current Farcaster username
+    profile_name: Option<String>,
 }
 
-async fn fetch_far_scores(
+pub async fn fetch_cached_far_scores(
     handle: String,
-    config: &Config,
-) -> Result<Option<FarStatsResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let request_body = FarScoresQuery::build_query(far_scores_query::Variables { handle: handle });
-    let client = reqwest::Client::builder()
-        .user_agent("graphql-rust/0.10.0")
-        .default_headers(
-            std::iter::once((
-                reqwest::header::AUTHORIZATION,
-                reqwest::header::HeaderValue::from_str(&format!(
-                    "Bearer {}",
-                    config.airstack_api_key
-                ))
-                .unwrap(),
-            ))
-            .collect(),
-        )
-        .build()
-        .unwrap();
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<Provided<FarStatsResponse>>, Freshness), (StatusCode, Json<serde_json::Value>)>
+{
+    // "@Dwr" and "dwr" share a cache entry
+    let handle = normalize_handle(&handle).ok_or((
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "Invalid handle"})),
+    ))?;
+    let cache_key = ScoreKey::Handle(handle.clone()).cache_key();
+    get_or_fetch(&cache_key, max_age, || fetch_far_scores(handle, state)).await
+}
 
-    let res = client
-        .post("https://api.airstack.xyz/gql")
-        .json(&request_body)
-        .send()
+async fn fetch_far_scores(
+    handle: String,
+    state: &AppState,
+) -> Result<Option<Provided<FarStatsResponse>>, (StatusCode, Json<serde_json::Value>)> {
+    let score = state
+        .providers
+        .social_score(&handle)
         .await
-        .unwrap();
+        .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
 
-    let response_body: Response<far_scores_query::ResponseData> = res.json().await.unwrap();
+    Ok(score.map(to_far_stats))
+}
 
-    let far_stats = response_body
-        .data
-        .as_ref()
-        .and_then(|d| d.socials.social.first())
-        .map(|s| FarStatsResponse {
-            far_score: s.social_capital.social_capital_score,
-            far_rank: s.social_capital.social_capital_rank,
-        })
-        .or(None);
+pub async fn fetch_cached_far_scores_by_fid(
+    fid: u64,
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<Provided<FarStatsResponse>>, Freshness), (StatusCode, Json<serde_json::Value>)>
+{
+    let cache_key = ScoreKey::Fid(fid).cache_key();
+    get_or_fetch(&cache_key, max_age, || async {
+        let score = state
+            .providers
+            .social_score_by_fid(fid)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+        Ok(score.map(to_far_stats))
+    })
+    .await
+}
 
-    Ok(far_stats)
+fn to_far_stats(score: Provided<SocialScore>) -> Provided<FarStatsResponse> {
+    Provided {
+        data: FarStatsResponse {
+            far_score: score.data.score,
+            far_rank: score.data.rank,
+            profile_name: score.data.profile_name,
+        },
+        provider: score.provider,
+    }
 }
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/airstack_schema.graphql",
-    query_path = "src/gql/far_scores_query.graphql",
-    response_derives = "Debug"
-)]
-pub struct FarScoresQuery;
+async fn fetch_far_scores_many(
+    keys: Vec<ScoreKey>,
+    state: &AppState,
+) -> Result<HashMap<ScoreKey, Provided<FarStatsResponse>>, (StatusCode, Json<serde_json::Value>)> {
+    let mut handles = Vec::new();
+    let mut fids = Vec::new();
+    for key in keys {
+        match key {
+            ScoreKey::Handle(handle) => handles.push(handle),
+            ScoreKey::Fid(fid) => fids.push(fid),
+        }
+    }
+    let scores = state
+        .providers
+        .social_scores_many(&handles, &fids)
+        .await
+        .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+
+    let Some(scores) = scores else {
+        return Ok(HashMap::new());
+    };
+    let provided = |score: SocialScore| {
+        to_far_stats(Provided {
+            data: score,
+            provider: scores.provider.clone(),
+        })
+    };
+    let by_handle = scores.data.by_handle.into_iter();
+    let by_fid = scores.data.by_fid.into_iter();
+    Ok(by_handle
+        .map(|(handle, score)| (ScoreKey::Handle(handle), provided(score)))
+        .chain(by_fid.map(|(fid, score)| (ScoreKey::Fid(fid), provided(score))))
+        .collect())
+}
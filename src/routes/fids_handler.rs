@@ -1,14 +1,18 @@
-use std::sync::Arc;
-
 use axum::{
     extract::{Query, State},
     http::{HeaderMap, StatusCode},
     Json,
 };
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::routes::config::Config;
+use crate::cache::{get_or_fetch, Freshness};
+use crate::providers::{FarcasterUser, Provided};
+use crate::routes::{max_age::MaxAge, AppState, ResponseMeta};
+use crate::warpcast::normalize_handle;
+
+pub const FID_DEFAULT_MAX_AGE: u64 = 24 * 60 * 60;
 
 #[derive(Deserialize)]
 pub struct FidRequestQuery {
@@ -20,9 +24,26 @@ pub struct FidResponse {
     fid: u64,
 }
 
+#[derive(Deserialize)]
+pub struct ResolveUsersRequest {
+    #[serde(default)]
+    handles: Vec<String>,
+    #[serde(default)]
+    fids: Vec<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedUser {
+    fid: u64,
+    username: Option<String>,
+    pfp_url: Option<String>,
+}
+
 pub async fn get_fid(
-    State(config): State<Arc<Config>>,
+    State(state): State<AppState>,
     headers: HeaderMap,
+    max_age: MaxAge,
     Query(params): Query<FidRequestQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Check API key
@@ -30,7 +51,7 @@ pub async fn get_fid(
         .get("x-me-api-key")
         .and_then(|value| value.to_str().ok());
 
-    if api_key != Some(&config.api_key) {
+    if api_key != Some(&state.config.api_key) {
         return Err((
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Unauthorized"})),
@@ -45,9 +66,14 @@ pub async fn get_fid(
         ));
     }
 
-    let fid = fetch_fid_from_wc(&params.handle.unwrap()).await.unwrap();
+    let (user, freshness) = fetch_user_by_handle(
+        &params.handle.unwrap(),
+        &state,
+        max_age.or(FID_DEFAULT_MAX_AGE),
+    )
+    .await?;
 
-    if fid.is_none() {
+    if user.is_none() {
         return Err((
             StatusCode::NOT_FOUND,
             Json(json!({"error": "User not found"})),
@@ -55,20 +81,138 @@ pub async fn get_fid(
     }
 
     // Parse and validate fid
-    Ok(Json(json!({"data": FidResponse { fid: fid.unwrap() }})))
+    let user = user.unwrap();
+    let meta = ResponseMeta {
+        freshness,
+        provider: Some(user.provider),
+    };
+
+    Ok(Json(json!({
+        "data": FidResponse { fid: user.data.fid },
+        "meta": meta,
+    })))
+}
+
+// Handler for POST /users/resolve. Resolves handles and FIDs to profiles, keyed by
+// normalized handle and FID. Lookups are deduplicated, cached and run a few at a time.
+pub async fn resolve_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+    Json(body): Json<ResolveUsersRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let mut handles: Vec<String> = body
+        .handles
+        .iter()
+        .map(|h| normalize_handle(h).unwrap_or(h.clone()))
+        .collect();
+    handles.sort_unstable();
+    handles.dedup();
+    let mut fids = body.fids;
+    fids.sort_unstable();
+    fids.dedup();
+    if handles.len() + fids.len() > state.config.batch_max_items {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("At most {} users per batch", state.config.batch_max_items)
+            })),
+        ));
+    }
+
+    let max_age = max_age.or(FID_DEFAULT_MAX_AGE);
+    let concurrency = state.config.resolve_concurrency;
+    let state = &state;
+    let by_handle: Vec<_> = stream::iter(handles.clone())
+        .map(|handle| async move { fetch_user_by_handle(&handle, state, max_age).await })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    let by_fid: Vec<_> = stream::iter(fids.clone())
+        .map(|fid| fetch_user_by_fid(fid, state, max_age))
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+    Ok(Json(json!({
+        "data": {
+            "handles": to_resolved_map(handles, by_handle),
+            "fids": to_resolved_map(fids, by_fid),
+        }
+    })))
+}
+
+type UserResult =
+    Result<(Option<Provided<FarcasterUser>>, Freshness), (StatusCode, Json<serde_json::Value>)>;
+
+fn to_resolved_map<K: ToString>(
+    keys: Vec<K>,
+    results: Vec<UserResult>,
+) -> serde_json::Map<String, serde_json::Value> {
+    keys.into_iter()
+        .zip(results)
+        .map(|(key, result)| {
+            let item = match result {
+                Ok((user, freshness)) => {
+                    let meta = ResponseMeta {
+                        freshness,
+                        provider: user.as_ref().map(|u| u.provider.clone()),
+                    };
+                    let user = user.map(|u| ResolvedUser {
+                        fid: u.data.fid,
+                        username: u.data.username,
+                        pfp_url: u.data.pfp_url,
+                    });
+                    json!({ "data": user, "meta": meta })
+                }
+                Err((_, error)) => error.0,
+            };
+            (key.to_string(), item)
+        })
+        .collect()
+}
+
+pub async fn fetch_user_by_fid(fid: u64, state: &AppState, max_age: u64) -> UserResult {
+    let cache_key = format!("farcasterUserByFid/{}", fid);
+    get_or_fetch(&cache_key, max_age, || async {
+        state
+            .providers
+            .user_by_fid(fid)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))
+    })
+    .await
 }
 
-async fn fetch_fid_from_wc(username: &str) -> Result<Option<u64>, Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://api.warpcast.com/v2/user-by-username?username={}",
-        username
-    );
-    let resp = reqwest::get(&url).await?;
-    let result: serde_json::Value = resp.json().await?;
-
-    Ok(result
-        .get("result")
-        .and_then(|r| r.get("user"))
-        .and_then(|u| u.get("fid"))
-        .and_then(|f| f.as_u64()))
+pub async fn fetch_user_by_handle(
+    handle: &str,
+    state: &AppState,
+    max_age: u64,
+) -> Result<(Option<Provided<FarcasterUser>>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    let handle = normalize_handle(handle).ok_or((
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "Invalid handle"})),
+    ))?;
+    // v2 since the user is cached with its provider
+    let cache_key = format!("farcasterUser/v2/{}", handle);
+    get_or_fetch(&cache_key, max_age, || async {
+        state
+            .providers
+            .user_by_handle(&handle)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))
+    })
+    .await
 }
@@ -0,0 +1,99 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header::CACHE_CONTROL, request::Parts, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+struct MaxAgeQuery {
+    #[serde(rename = "maxAge")]
+    max_age: Option<u64>,
+}
+
+// Maximum age (in seconds) of cached data the client accepts, taken from the `maxAge`
+// query param or the `Cache-Control: max-age` / `no-cache` request header
+pub struct MaxAge(pub Option<u64>);
+
+impl MaxAge {
+    pub fn or(&self, default: u64) -> u64 {
+        self.0.unwrap_or(default)
+    }
+}
+
+// directive names are case-insensitive, `MAX-AGE=60` is as valid as `max-age=60`
+fn parse_cache_control(value: &str) -> Option<u64> {
+    value.split(',').map(|d| d.trim()).find_map(|directive| {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (directive, None),
+        };
+        if name.eq_ignore_ascii_case("no-cache") {
+            Some(0)
+        } else if name.eq_ignore_ascii_case("max-age") {
+            value.and_then(|v| v.trim_matches('"').parse::<u64>().ok())
+        } else {
+            None
+        }
+    })
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for MaxAge {
+    type Rejection = (StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = Query::<MaxAgeQuery>::try_from_uri(&parts.uri).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid maxAge"})),
+            )
+        })?;
+        if let Some(max_age) = query.max_age {
+            return Ok(MaxAge(Some(max_age)));
+        }
+
+        let max_age = parts
+            .headers
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_cache_control);
+
+        Ok(MaxAge(max_age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cache_control;
+
+    #[test]
+    fn parses_max_age() {
+        assert_eq!(parse_cache_control("max-age=60"), Some(60));
+        assert_eq!(parse_cache_control("public, max-age=300"), Some(300));
+        assert_eq!(parse_cache_control("max-age=\"30\""), Some(30));
+    }
+
+    #[test]
+    fn directive_names_are_case_insensitive() {
+        assert_eq!(parse_cache_control("MAX-AGE=60"), Some(60));
+        assert_eq!(parse_cache_control("Max-Age = 10"), Some(10));
+        assert_eq!(parse_cache_control("No-Cache"), Some(0));
+    }
+
+    #[test]
+    fn no_cache_means_zero() {
+        assert_eq!(parse_cache_control("no-cache"), Some(0));
+        assert_eq!(parse_cache_control("no-cache, max-age=60"), Some(0));
+    }
+
+    #[test]
+    fn ignores_other_and_invalid_directives() {
+        assert_eq!(parse_cache_control("no-store"), None);
+        assert_eq!(parse_cache_control("max-age=soon"), None);
+        assert_eq!(parse_cache_control("s-maxage=60"), None);
+        assert_eq!(parse_cache_control(""), None);
+    }
+}
@@ -1,14 +1,60 @@
-use std::{env, sync::Arc};
+use std::sync::Arc;
 
-use axum::{routing::get, Router};
+use axum::{
+    extract::FromRef,
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use serde::Serialize;
 
+use crate::airstack::batch::CastBatcher;
+use crate::cache::{set_redis_mode, Freshness};
+use crate::providers::Providers;
+
+mod airstack_keys_handler;
+mod cache_warmer;
 mod cast_earnings_handler;
 mod cast_embeds_handler;
-mod config;
+mod cast_handler;
+mod cast_overview_handler;
+mod channel_earnings_handler;
+pub mod config;
+mod deadline;
 mod far_scores_handler;
-mod fetch_cast_from_neynar;
 mod fids_handler;
+mod max_age;
 mod user_earnings_handler;
+mod user_summary_handler;
+
+// `meta` of the response envelope
+#[derive(Serialize)]
+pub struct ResponseMeta {
+    #[serde(flatten)]
+    pub freshness: Freshness,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    config: Arc<config::Config>,
+    tracker: Arc<cache_warmer::RequestTracker>,
+    providers: Arc<Providers>,
+    casts: Arc<CastBatcher>,
+}
+
+impl FromRef<AppState> for Arc<config::Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<cache_warmer::RequestTracker> {
+    fn from_ref(state: &AppState) -> Self {
+        state.tracker.clone()
+    }
+}
 
 pub fn api_routes() -> Router {
     async fn options_handler() -> impl axum::response::IntoResponse {
@@ -29,26 +75,83 @@ pub fn api_routes() -> Router {
             .unwrap()
     }
 
+    let config = Arc::new(config::Config::from_env());
+    set_redis_mode(config.redis_mode);
+    let casts = Arc::new(CastBatcher::new(config.clone()));
+    let state = AppState {
+        providers: Arc::new(Providers::new(config.clone(), casts.clone())),
+        casts,
+        config,
+        tracker: Arc::new(cache_warmer::RequestTracker::new()),
+    };
+    cache_warmer::spawn(state.clone());
+
     Router::new()
         .route(
             "/users/:fid/earnings",
             get(user_earnings_handler::get_user_earnings).options(options_handler),
         )
+        .route(
+            "/users/by-handle/:handle/earnings",
+            get(user_earnings_handler::get_user_earnings_by_handle).options(options_handler),
+        )
+        // `:fid` also takes a handle here, path params must share their name
+        .route(
+            "/users/:fid/summary",
+            get(user_summary_handler::get_user_summary).options(options_handler),
+        )
+        .route(
+            "/users/:fid/far-score",
+            get(far_scores_handler::get_user_far_score).options(options_handler),
+        )
+        .route(
+            "/users/earnings/batch",
+            post(user_earnings_handler::get_user_earnings_batch).options(options_handler),
+        )
         .route("/fids", get(fids_handler::get_fid).options(options_handler))
+        .route(
+            "/users/resolve",
+            post(fids_handler::resolve_users).options(options_handler),
+        )
         .route(
             "/far-scores",
             get(far_scores_handler::get_far_scores).options(options_handler),
         )
+        .route(
+            "/far-scores/batch",
+            post(far_scores_handler::get_far_scores_batch).options(options_handler),
+        )
         .route(
             "/casts/embeds",
             get(cast_embeds_handler::get_cast_embeds).options(options_handler),
         )
+        .route(
+            "/casts/overview",
+            get(cast_overview_handler::get_cast_overview).options(options_handler),
+        )
+        .route(
+            "/channels/:channel_id/earnings",
+            get(channel_earnings_handler::get_channel_earnings).options(options_handler),
+        )
+        .route(
+            "/casts/:hash_or_url",
+            get(cast_handler::get_cast).options(options_handler),
+        )
         .route(
             "/earnings",
             get(cast_earnings_handler::get_cast_earnings).options(options_handler),
         )
-        .with_state(Arc::new(config::Config {
-            api_key: env::var("API_KEY").expect("API_KEY must be set"),
-            airstack_api_key: env::var("AIRSTACK_API_KEY").expect("AIRSTACK_API_KEY must be set"),
-        }))
+        .route(
+            "/earnings/batch",
+            post(cast_earnings_handler::get_cast_earnings_batch).options(options_handler),
+        )
+        .route(
+            "/airstack/keys",
+            get(airstack_keys_handler::get_airstack_keys).options(options_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            deadline::enforce_deadline,
+        ))
+        .with_state(state)
 }
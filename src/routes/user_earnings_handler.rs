@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::{
@@ -5,17 +6,33 @@ use axum::{
     http::{HeaderMap, StatusCode},
     Json,
 };
+use futures::future::try_join_all;
 use graphql_client::{GraphQLQuery, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::routes::config::Config;
+use crate::airstack::{fetch_query, AIRSTACK_PAGE_SIZE};
+use crate::cache::{get_or_fetch, get_or_fetch_many, Freshness};
+use crate::providers::{airstack::AirstackProvider, Provided};
+use crate::routes::{
+    cache_warmer::RequestTracker,
+    config::Config,
+    fids_handler::{fetch_user_by_handle, FID_DEFAULT_MAX_AGE},
+    max_age::MaxAge,
+    AppState, ResponseMeta,
+};
+
+use moxie_earnings_query::EntityType;
+
+pub const USER_EARNINGS_DEFAULT_MAX_AGE: u64 = 60;
 
 // Handler for GET /earnings/:fid
 pub async fn get_user_earnings(
     State(config): State<Arc<Config>>,
+    State(tracker): State<Arc<RequestTracker>>,
     Path(fid): Path<String>,
     headers: HeaderMap,
+    max_age: MaxAge,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Check API key
     let api_key = headers
@@ -38,29 +55,174 @@ pub async fn get_user_earnings(
     })?;
 
     // Fetch earnings (you'll need to implement this function)
-    let earnings = fetch_earnings(fid, &config).await?;
+    tracker.record_fid(fid);
+    let (earnings, freshness) =
+        fetch_cached_user_earnings(fid, &config, max_age.or(USER_EARNINGS_DEFAULT_MAX_AGE)).await?;
+    let meta = ResponseMeta {
+        freshness,
+        provider: earnings.as_ref().map(|e| e.provider.clone()),
+    };
+
+    Ok(Json(
+        json!({"data": earnings.map(|e| e.data), "meta": meta}),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct UserEarningsByHandle {
+    fid: u64,
+    #[serde(flatten)]
+    earnings: Option<UserEarnings>,
+}
+
+// `provider` served the earnings, `resolved_by` the handle's FID
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EarningsByHandleMeta {
+    #[serde(flatten)]
+    meta: ResponseMeta,
+    resolved_by: String,
+}
+
+// Handler for GET /users/by-handle/:handle/earnings, resolves the handle to a FID first
+pub async fn get_user_earnings_by_handle(
+    State(state): State<AppState>,
+    Path(handle): Path<String>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let (user, _) = fetch_user_by_handle(&handle, &state, FID_DEFAULT_MAX_AGE).await?;
+    let user = user.ok_or((
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "User not found"})),
+    ))?;
+    let fid = user.data.fid;
+
+    state.tracker.record_fid(fid);
+    let (earnings, freshness) = fetch_cached_user_earnings(
+        fid,
+        &state.config,
+        max_age.or(USER_EARNINGS_DEFAULT_MAX_AGE),
+    )
+    .await?;
+    let meta = EarningsByHandleMeta {
+        meta: ResponseMeta {
+            freshness,
+            provider: earnings.as_ref().map(|e| e.provider.clone()),
+        },
+        resolved_by: user.provider,
+    };
+    let earnings = earnings.map(|e| e.data);
+
+    Ok(Json(json!({
+        "data": UserEarningsByHandle { fid, earnings },
+        "meta": meta,
+    })))
+}
 
-    Ok(Json(json!({"data": earnings})))
+#[derive(Deserialize)]
+pub struct UserEarningsBatchRequest {
+    fids: Vec<u64>,
+}
+
+// Handler for POST /users/earnings/batch, earnings of many users keyed by FID. FIDs
+// missing from the cache are fetched with a single Airstack query.
+pub async fn get_user_earnings_batch(
+    State(config): State<Arc<Config>>,
+    State(tracker): State<Arc<RequestTracker>>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+    Json(body): Json<UserEarningsBatchRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let mut fids = body.fids;
+    fids.sort_unstable();
+    fids.dedup();
+    if fids.len() > config.batch_max_items {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("At most {} FIDs per batch", config.batch_max_items)
+            })),
+        ));
+    }
+
+    for fid in &fids {
+        tracker.record_fid(*fid);
+    }
+    let entries = fids
+        .iter()
+        .map(|fid| (*fid, user_earnings_cache_key(*fid)))
+        .collect();
+    let results = get_or_fetch_many(
+        entries,
+        max_age.or(USER_EARNINGS_DEFAULT_MAX_AGE),
+        |missing| fetch_earnings_many(missing, &config),
+    )
+    .await;
+
+    let data: serde_json::Map<String, serde_json::Value> = fids
+        .into_iter()
+        .zip(results)
+        .map(|(fid, result)| {
+            let item = match result {
+                Ok((earnings, freshness)) => {
+                    let meta = ResponseMeta {
+                        freshness,
+                        provider: earnings.as_ref().map(|e| e.provider.clone()),
+                    };
+                    json!({ "data": earnings.map(|e| e.data), "meta": meta })
+                }
+                Err((_, error)) => error.0,
+            };
+            (fid.to_string(), item)
+        })
+        .collect();
+
+    Ok(Json(json!({ "data": data })))
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct AirstackEarningStat {
+pub struct AirstackEarningStat {
     all_earnings_amount: f64,
     cast_earnings_amount: f64,
     frame_dev_earnings_amount: f64,
     other_earnings_amount: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
-struct UserEarnings {
+pub struct UserEarnings {
     today: Option<AirstackEarningStat>,
     weekly: Option<AirstackEarningStat>,
     lifetime: Option<AirstackEarningStat>,
 }
 
-fn to_airstack_earning_stat(
+pub fn to_airstack_earning_stat(
     stat: Option<&moxie_earnings_query::FarcasterMoxieEarningStatFragment>,
 ) -> Option<AirstackEarningStat> {
     stat.map(|s| AirstackEarningStat {
@@ -71,51 +233,124 @@ fn to_airstack_earning_stat(
     })
 }
 
+// v2 entries hold the earnings along with the provider that served them
+fn user_earnings_cache_key(fid: u64) -> String {
+    format!("userEarnings/v2/{}", fid)
+}
+
+// earnings keep the provider they came from, also when served from the cache
+pub fn from_airstack<T>(data: T) -> Provided<T> {
+    Provided {
+        data,
+        provider: AirstackProvider::NAME.to_string(),
+    }
+}
+
+pub async fn fetch_cached_user_earnings(
+    fid: u64,
+    config: &Config,
+    max_age: u64,
+) -> Result<(Option<Provided<UserEarnings>>, Freshness), (StatusCode, Json<serde_json::Value>)> {
+    get_or_fetch(&user_earnings_cache_key(fid), max_age, || async {
+        let earnings = fetch_earnings(fid, config).await?;
+        Ok(earnings.map(from_airstack))
+    })
+    .await
+}
+
 async fn fetch_earnings(
     fid: u64,
     config: &Config,
 ) -> Result<Option<UserEarnings>, (StatusCode, Json<serde_json::Value>)> {
     let request_body = MoxieEarningsQuery::build_query(moxie_earnings_query::Variables {
-        fid: fid.to_string(),
+        entity_type: EntityType::USER,
+        entity_id: fid.to_string(),
     });
-    let client = reqwest::Client::builder()
-        .user_agent("graphql-rust/0.10.0")
-        .default_headers(
-            std::iter::once((
-                reqwest::header::AUTHORIZATION,
-                reqwest::header::HeaderValue::from_str(&format!(
-                    "Bearer {}",
-                    config.airstack_api_key
-                ))
-                .unwrap(),
-            ))
-            .collect(),
-        )
-        .build()
-        .unwrap();
-
-    let res = client
-        .post("https://api.airstack.xyz/gql")
-        .json(&request_body)
-        .send()
-        .await
-        .unwrap();
-
-    // let response_body = res.json().await.unwrap();
-    let response_body: Response<moxie_earnings_query::ResponseData> = res.json().await.unwrap();
+    let response_body =
+        fetch_query::<_, Response<moxie_earnings_query::ResponseData>>(config, &request_body)
+            .await
+            .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
     // println!("response_body: {:?}", response_body);
+    // without any stats there are no earnings, as in the batch lookup
     let earnings = response_body
         .data
         .map(|d| UserEarnings {
-            today: to_airstack_earning_stat(d.today.farcaster_moxie_earning_stat.get(0)),
-            weekly: to_airstack_earning_stat(d.weekly.farcaster_moxie_earning_stat.get(0)),
-            lifetime: to_airstack_earning_stat(d.lifetime.farcaster_moxie_earning_stat.get(0)),
+            today: to_airstack_earning_stat(d.today.farcaster_moxie_earning_stat.first()),
+            weekly: to_airstack_earning_stat(d.weekly.farcaster_moxie_earning_stat.first()),
+            lifetime: to_airstack_earning_stat(d.lifetime.farcaster_moxie_earning_stat.first()),
         })
-        .or(None);
+        .filter(|e| e.today.is_some() || e.weekly.is_some() || e.lifetime.is_some());
 
     Ok(earnings)
 }
 
+// Earnings of several users with one query per page of FIDs, FIDs without any stats
+// are left out
+async fn fetch_earnings_many(
+    fids: Vec<u64>,
+    config: &Config,
+) -> Result<HashMap<u64, Provided<UserEarnings>>, (StatusCode, Json<serde_json::Value>)> {
+    let pages = try_join_all(
+        fids.chunks(AIRSTACK_PAGE_SIZE)
+            .map(|page| fetch_earnings_page(page, config)),
+    )
+    .await?;
+    Ok(pages
+        .into_iter()
+        .flatten()
+        .map(|(fid, earnings)| (fid, from_airstack(earnings)))
+        .collect())
+}
+
+// each FID has at most one stat per timeframe, so the page fits in one response
+async fn fetch_earnings_page(
+    fids: &[u64],
+    config: &Config,
+) -> Result<HashMap<u64, UserEarnings>, (StatusCode, Json<serde_json::Value>)> {
+    let request_body =
+        MoxieEarningsByFidsQuery::build_query(moxie_earnings_by_fids_query::Variables {
+            fids: fids.iter().map(|fid| fid.to_string()).collect(),
+            limit: fids.len() as i64,
+        });
+    let response_body = fetch_query::<_, Response<moxie_earnings_by_fids_query::ResponseData>>(
+        config,
+        &request_body,
+    )
+    .await
+    .map_err(|e| (e.status_code(), Json(json!({"error": e.to_string()}))))?;
+
+    let mut earnings: HashMap<u64, UserEarnings> = HashMap::new();
+    if let Some(d) = response_body.data {
+        for s in d.today.farcaster_moxie_earning_stat {
+            if let Ok(fid) = s.entity_id.parse() {
+                earnings.entry(fid).or_default().today = Some(to_batched_earning_stat(&s));
+            }
+        }
+        for s in d.weekly.farcaster_moxie_earning_stat {
+            if let Ok(fid) = s.entity_id.parse() {
+                earnings.entry(fid).or_default().weekly = Some(to_batched_earning_stat(&s));
+            }
+        }
+        for s in d.lifetime.farcaster_moxie_earning_stat {
+            if let Ok(fid) = s.entity_id.parse() {
+                earnings.entry(fid).or_default().lifetime = Some(to_batched_earning_stat(&s));
+            }
+        }
+    }
+    Ok(earnings)
+}
+
+fn to_batched_earning_stat(
+    s: &moxie_earnings_by_fids_query::FarcasterMoxieEarningStatFragment,
+) -> AirstackEarningStat {
+    AirstackEarningStat {
+        all_earnings_amount: s.all_earnings_amount,
+        cast_earnings_amount: s.cast_earnings_amount,
+        frame_dev_earnings_amount: s.frame_dev_earnings_amount,
+        other_earnings_amount: s.other_earnings_amount,
+    }
+}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "src/gql/airstack_schema.graphql",
@@ -123,3 +358,11 @@ async fn fetch_earnings(
     response_derives = "Debug"
 )]
 pub struct MoxieEarningsQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/airstack_schema.graphql",
+    query_path = "src/gql/moxie_earnings_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct MoxieEarningsByFidsQuery;
@@ -0,0 +1,159 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::cache::Freshness;
+use crate::providers::{FarcasterUser, Provided};
+use crate::routes::{
+    far_scores_handler::{
+        fetch_cached_far_scores, fetch_cached_far_scores_by_fid, FAR_SCORES_DEFAULT_MAX_AGE,
+    },
+    fids_handler::{fetch_user_by_fid, fetch_user_by_handle, FID_DEFAULT_MAX_AGE},
+    max_age::MaxAge,
+    user_earnings_handler::{fetch_cached_user_earnings, USER_EARNINGS_DEFAULT_MAX_AGE},
+    AppState, ResponseMeta,
+};
+use crate::warpcast::normalize_handle;
+
+type ErrorResponse = (StatusCode, Json<serde_json::Value>);
+type SectionResult<T> = Result<(Option<T>, ResponseMeta), ErrorResponse>;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SummaryProfile {
+    username: Option<String>,
+    display_name: Option<String>,
+    pfp_url: Option<String>,
+}
+
+fn with_provider<T, U>(
+    result: Result<(Option<Provided<T>>, Freshness), ErrorResponse>,
+    map: impl FnOnce(T) -> U,
+) -> SectionResult<U> {
+    result.map(|(data, freshness)| {
+        let meta = ResponseMeta {
+            freshness,
+            provider: data.as_ref().map(|d| d.provider.clone()),
+        };
+        (data.map(|d| map(d.data)), meta)
+    })
+}
+
+// One part of the summary with its own status, so a failing upstream only blanks
+// its own section
+fn section<T: Serialize>(result: SectionResult<T>) -> serde_json::Value {
+    match result {
+        Ok((Some(data), meta)) => json!({"status": "ok", "data": data, "meta": meta}),
+        Ok((None, meta)) => json!({"status": "notFound", "data": null, "meta": meta}),
+        Err((_, error)) => json!({"status": "error", "error": error.0["error"]}),
+    }
+}
+
+fn to_profile(user: FarcasterUser) -> SummaryProfile {
+    SummaryProfile {
+        username: user.username,
+        display_name: user.display_name,
+        pfp_url: user.pfp_url,
+    }
+}
+
+// Handler for GET /users/:fid/summary, which takes a FID or a handle. All-digit input
+// is read as a FID, handles made of digits only need their `@` (e.g. `/users/@1234/summary`).
+// Profile, far score and earnings are fetched concurrently.
+pub async fn get_user_summary(
+    State(state): State<AppState>,
+    Path(id_or_handle): Path<String>,
+    headers: HeaderMap,
+    max_age: MaxAge,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Check API key
+    let api_key = headers
+        .get("x-me-api-key")
+        .and_then(|value| value.to_str().ok());
+
+    if api_key != Some(&state.config.api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        ));
+    }
+
+    let profile_max_age = max_age.or(FID_DEFAULT_MAX_AGE);
+    let far_score_max_age = max_age.or(FAR_SCORES_DEFAULT_MAX_AGE);
+    let earnings_max_age = max_age.or(USER_EARNINGS_DEFAULT_MAX_AGE);
+
+    let (fid, profile, far_score, earnings) = match id_or_handle.parse::<u64>() {
+        Ok(fid) => {
+            state.tracker.record_fid(fid);
+            let (profile, far_score, earnings) = tokio::join!(
+                fetch_user_by_fid(fid, &state, profile_max_age),
+                fetch_cached_far_scores_by_fid(fid, &state, far_score_max_age),
+                fetch_cached_user_earnings(fid, &state.config, earnings_max_age),
+            );
+            (
+                Some(fid),
+                with_provider(profile, to_profile),
+                with_provider(far_score, |s| s),
+                with_provider(earnings, |e| e),
+            )
+        }
+        Err(_) => {
+            let handle = normalize_handle(&id_or_handle).ok_or((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid handle"})),
+            ))?;
+            state.tracker.record_handle(&handle);
+            // earnings need the FID, so they wait for the profile
+            let profile_and_earnings = async {
+                let profile = fetch_user_by_handle(&handle, &state, profile_max_age).await;
+                let (fid, earnings) = match &profile {
+                    Ok((Some(user), _)) => {
+                        let fid = user.data.fid;
+                        state.tracker.record_fid(fid);
+                        let earnings =
+                            fetch_cached_user_earnings(fid, &state.config, earnings_max_age).await;
+                        (Some(fid), earnings)
+                    }
+                    Ok((None, _)) => (
+                        None,
+                        Err((
+                            StatusCode::NOT_FOUND,
+                            Json(json!({"error": "User not found"})),
+                        )),
+                    ),
+                    Err(_) => (
+                        None,
+                        Err((
+                            StatusCode::BAD_GATEWAY,
+                            Json(json!({"error": "Unable to resolve the user's FID"})),
+                        )),
+                    ),
+                };
+                (fid, profile, earnings)
+            };
+            let ((fid, profile, earnings), far_score) = tokio::join!(
+                profile_and_earnings,
+                fetch_cached_far_scores(handle.clone(), &state, far_score_max_age),
+            );
+            (
+                fid,
+                with_provider(profile, to_profile),
+                with_provider(far_score, |s| s),
+                with_provider(earnings, |e| e),
+            )
+        }
+    };
+
+    Ok(Json(json!({
+        "data": {
+            "fid": fid,
+            "profile": section(profile),
+            "farScore": section(far_score),
+            "earnings": section(earnings),
+        }
+    })))
+}
@@ -0,0 +1,43 @@
+use std::cell::Cell;
+use std::future::Future;
+
+tokio::task_local! {
+    static CALLS: Cell<u64>;
+}
+
+// Runs the future and returns how many upstream calls it made
+pub async fn count_calls<F: Future>(f: F) -> (F::Output, u64) {
+    CALLS
+        .scope(Cell::new(0), async {
+            let output = f.await;
+            (output, CALLS.with(|calls| calls.get()))
+        })
+        .await
+}
+
+// Counts one upstream call against the current `count_calls`, if any
+pub fn record_call() {
+    let _ = CALLS.try_with(|calls| calls.set(calls.get() + 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_calls_made_within_the_scope() {
+        let ((), calls) = count_calls(async {
+            record_call();
+            record_call();
+        })
+        .await;
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn ignores_calls_outside_of_a_scope() {
+        record_call();
+        let ((), calls) = count_calls(async {}).await;
+        assert_eq!(calls, 0);
+    }
+}
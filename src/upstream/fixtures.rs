@@ -0,0 +1,241 @@
+use std::{env, path::PathBuf, str::FromStr};
+
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::upstream::{calls::record_call, deadline::remaining, UpstreamError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixtureMode {
+    // talk to the upstreams as usual
+    Off,
+    // talk to the upstreams and save every response as a fixture
+    Record,
+    // serve saved fixtures only, without any network access
+    Replay,
+}
+
+impl FromStr for FixtureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "off" => Ok(FixtureMode::Off),
+            "record" => Ok(FixtureMode::Record),
+            "replay" => Ok(FixtureMode::Replay),
+            _ => Err(format!("Unknown fixture mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    pub mode: FixtureMode,
+    pub dir: PathBuf,
+}
+
+impl Fixtures {
+    // reads UPSTREAM_FIXTURES_MODE and UPSTREAM_FIXTURES_DIR
+    pub fn from_env() -> Self {
+        Self {
+            mode: env::var("UPSTREAM_FIXTURES_MODE")
+                .unwrap_or_default()
+                .parse()
+                .unwrap_or_else(|e| panic!("UPSTREAM_FIXTURES_MODE: {}", e)),
+            dir: env::var("UPSTREAM_FIXTURES_DIR")
+                .unwrap_or("fixtures".to_string())
+                .into(),
+        }
+    }
+}
+
+// Headers worth keeping, the rest is noise that changes on every request
+const RECORDED_HEADERS: [&str; 2] = ["content-type", "retry-after"];
+
+#[derive(Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    body: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    request: RecordedRequest,
+    status: u16,
+    headers: Vec<(String, String)>,
+    // JSON bodies are stored as is to keep fixtures readable, anything else as a string
+    body: Value,
+}
+
+// Sends the request within the remaining deadline, or records/replays it depending on
+// the fixture mode. Fixtures are keyed by upstream, method, URL and body; request
+// headers (API keys) are never saved.
+pub async fn send(
+    fixtures: &Fixtures,
+    upstream: &str,
+    request: RequestBuilder,
+) -> Result<Response, UpstreamError> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    record_call();
+    // only the time left of the current request, on top of the client's own timeout
+    if let Some(remaining) = remaining() {
+        if remaining.is_zero() {
+            return Err(UpstreamError::DeadlineExceeded);
+        }
+        let timeout = request.timeout().map_or(remaining, |t| (*t).min(remaining));
+        *request.timeout_mut() = Some(timeout);
+    }
+    if fixtures.mode == FixtureMode::Off {
+        return Ok(client.execute(request).await?);
+    }
+
+    let body = request.body().and_then(|b| b.as_bytes()).map(to_value);
+    let recorded = RecordedRequest {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        body,
+    };
+    let path = fixtures
+        .dir
+        .join(upstream.to_lowercase())
+        .join(format!("{:016x}.json", fixture_key(&recorded)));
+
+    let fixture = match fixtures.mode {
+        FixtureMode::Replay => {
+            let contents = tokio::fs::read(&path)
+                .await
+                .map_err(|_| UpstreamError::MissingFixture(path.display().to_string()))?;
+            serde_json::from_slice::<Fixture>(&contents)
+                .map_err(|_| UpstreamError::MissingFixture(path.display().to_string()))?
+        }
+        _ => {
+            let resp = client.execute(request).await?;
+            let status = resp.status().as_u16();
+            let headers = RECORDED_HEADERS
+                .iter()
+                .filter_map(|name| {
+                    let value = resp.headers().get(*name)?.to_str().ok()?;
+                    Some((name.to_string(), value.to_string()))
+                })
+                .collect();
+            let body = to_value(&resp.bytes().await?);
+            let fixture = Fixture {
+                request: recorded,
+                status,
+                headers,
+                body,
+            };
+            if let Err(e) = save(&path, &fixture).await {
+                eprintln!("Failed to record fixture {}: {}", path.display(), e);
+            }
+            fixture
+        }
+    };
+    Ok(to_response(fixture))
+}
+
+fn to_value(bytes: &[u8]) -> Value {
+    serde_json::from_slice(bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+// FNV-1a, stable across builds unlike the std hasher
+fn fixture_key(request: &RecordedRequest) -> u64 {
+    let key = serde_json::to_vec(request).unwrap_or_default();
+    key.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+async fn save(path: &PathBuf, fixture: &Fixture) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(path, serde_json::to_vec_pretty(fixture)?).await
+}
+
+fn to_response(fixture: Fixture) -> Response {
+    let body = match fixture.body {
+        Value::String(s) => s,
+        value => value.to_string(),
+    };
+    let mut builder = axum::http::Response::builder().status(fixture.status);
+    for (name, value) in &fixture.headers {
+        builder = builder.header(name, value);
+    }
+    builder
+        .body(body)
+        .unwrap_or_else(|_| axum::http::Response::new(String::new()))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Json, Router};
+    use serde_json::json;
+
+    use super::*;
+
+    async fn stub_upstream() -> String {
+        let app = Router::new().route(
+            "/gql",
+            post(|Json(body): Json<Value>| async move {
+                (
+                    [("retry-after", "7"), ("x-request-id", "abc")],
+                    Json(json!({"data": {"echo": body["query"]}})),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/gql", addr)
+    }
+
+    fn request(url: &str, query: &str) -> RequestBuilder {
+        reqwest::Client::new()
+            .post(url)
+            .bearer_auth("secret-key")
+            .json(&json!({ "query": query }))
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_responses() {
+        let dir = env::temp_dir().join(format!("fixtures-test-{}", std::process::id()));
+        let url = stub_upstream().await;
+        let record = Fixtures {
+            mode: FixtureMode::Record,
+            dir: dir.clone(),
+        };
+        let recorded = send(&record, "Airstack", request(&url, "{ a }"))
+            .await
+            .unwrap();
+        assert_eq!(recorded.status(), 200);
+        let recorded: Value = recorded.json().await.unwrap();
+
+        // replay never reaches the network, the URL only serves as part of the key
+        let replay = Fixtures {
+            mode: FixtureMode::Replay,
+            dir: dir.clone(),
+        };
+        let replayed = send(&replay, "Airstack", request(&url, "{ a }"))
+            .await
+            .unwrap();
+        assert_eq!(replayed.status(), 200);
+        assert_eq!(replayed.headers()["retry-after"], "7");
+        assert!(replayed.headers().get("x-request-id").is_none());
+        assert_eq!(replayed.json::<Value>().await.unwrap(), recorded);
+
+        let other = send(&replay, "Airstack", request(&url, "{ b }")).await;
+        assert!(matches!(other, Err(UpstreamError::MissingFixture(_))));
+
+        // API keys are sent as headers, which are never saved
+        let mut files = std::fs::read_dir(dir.join("airstack")).unwrap();
+        let saved = std::fs::read_to_string(files.next().unwrap().unwrap().path()).unwrap();
+        assert!(!saved.contains("secret-key"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
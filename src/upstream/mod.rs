@@ -0,0 +1,83 @@
+use std::fmt;
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+
+pub mod calls;
+pub mod deadline;
+pub mod fixtures;
+pub mod rate_limit;
+
+use rate_limit::RateLimitError;
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Http(reqwest::Error),
+    RateLimited(RateLimitError),
+    Status {
+        upstream: String,
+        status: reqwest::StatusCode,
+    },
+    NotConfigured(String),
+    // the response body couldn't be decoded
+    InvalidResponse {
+        upstream: String,
+        error: String,
+    },
+    // replay mode found no recorded response for the request
+    MissingFixture(String),
+    // the request ran out of time before the upstream call could be made
+    DeadlineExceeded,
+    // one failure reported to every caller of a batched request
+    Shared(Arc<UpstreamError>),
+}
+
+impl UpstreamError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            UpstreamError::Http(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UpstreamError::RateLimited(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UpstreamError::Status { .. } => StatusCode::BAD_GATEWAY,
+            UpstreamError::NotConfigured(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UpstreamError::InvalidResponse { .. } => StatusCode::BAD_GATEWAY,
+            UpstreamError::MissingFixture(_) => StatusCode::BAD_GATEWAY,
+            UpstreamError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::Shared(e) => e.status_code(),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Http(e) if e.is_timeout() => write!(f, "Request timed out"),
+            UpstreamError::Http(e) => write!(f, "{}", e),
+            UpstreamError::RateLimited(e) => write!(f, "{}", e),
+            UpstreamError::Status { upstream, status } => {
+                write!(f, "{} responded with {}", upstream, status)
+            }
+            UpstreamError::NotConfigured(what) => write!(f, "{} is not configured", what),
+            UpstreamError::InvalidResponse { upstream, error } => {
+                write!(f, "{} sent an invalid response: {}", upstream, error)
+            }
+            UpstreamError::MissingFixture(path) => write!(f, "No fixture recorded at {}", path),
+            UpstreamError::DeadlineExceeded => write!(f, "Request timed out"),
+            UpstreamError::Shared(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(e: reqwest::Error) -> Self {
+        UpstreamError::Http(e)
+    }
+}
+
+impl From<RateLimitError> for UpstreamError {
+    fn from(e: RateLimitError) -> Self {
+        UpstreamError::RateLimited(e)
+    }
+}
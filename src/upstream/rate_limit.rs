@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::cache::{incr, now_secs};
+use crate::upstream::{deadline::remaining, UpstreamError};
+
+// share of the monthly quota (in percent) at which a warning is logged
+const QUOTA_ALERT_THRESHOLDS: [u64; 3] = [80, 90, 100];
+
+#[derive(Debug, Clone)]
+pub struct UpstreamLimits {
+    // 0 disables the per-second limit
+    pub per_second: u32,
+    pub max_queue: usize,
+    pub queue_timeout: Duration,
+    pub monthly_quota: Option<u64>,
+}
+
+impl UpstreamLimits {
+    // reads e.g. AIRSTACK_RATE_LIMIT_PER_SECOND, AIRSTACK_QUEUE_SIZE,
+    // AIRSTACK_QUEUE_TIMEOUT_MS and AIRSTACK_MONTHLY_QUOTA for the `AIRSTACK` prefix
+    pub fn from_env(prefix: &str) -> Self {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name)).ok();
+        Self {
+            per_second: var("RATE_LIMIT_PER_SECOND")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            max_queue: var("QUEUE_SIZE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+            queue_timeout: Duration::from_millis(
+                var("QUEUE_TIMEOUT_MS")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(5000),
+            ),
+            monthly_quota: var("MONTHLY_QUOTA").and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    QueueFull { upstream: String },
+    QueueTimeout { upstream: String },
+    // every API key of the upstream is rate limited
+    KeysExhausted { upstream: String },
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::QueueFull { upstream } => write!(
+                f,
+                "Too many pending {} requests, please try again later",
+                upstream
+            ),
+            RateLimitError::QueueTimeout { upstream } => write!(
+                f,
+                "Timed out waiting for a {} rate limit slot, please try again later",
+                upstream
+            ),
+            RateLimitError::KeysExhausted { upstream } => write!(
+                f,
+                "All {} API keys are rate limited, please try again later",
+                upstream
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// local count, only used while Redis can't be reached
+struct MonthlyUsage {
+    month: (i64, u64),
+    consumed: u64,
+}
+
+// Token bucket for one upstream API key. Callers wait in FIFO order for a token until
+// `queue_timeout` elapses, and are rejected right away once `max_queue` callers wait.
+pub struct UpstreamLimiter {
+    upstream: String,
+    // upstream name with the API key suffix, used in logs only
+    label: String,
+    // Redis key prefix of the monthly usage counter, the API key is only kept hashed
+    usage_key: String,
+    limits: UpstreamLimits,
+    bucket: tokio::sync::Mutex<Bucket>,
+    queued: AtomicUsize,
+    usage: Arc<Mutex<MonthlyUsage>>,
+}
+
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl UpstreamLimiter {
+    fn new(upstream: String, label: String, usage_key: String, limits: UpstreamLimits) -> Self {
+        Self {
+            upstream,
+            label,
+            usage_key,
+            bucket: tokio::sync::Mutex::new(Bucket {
+                tokens: limits.per_second as f64,
+                last_refill: Instant::now(),
+            }),
+            limits,
+            queued: AtomicUsize::new(0),
+            usage: Arc::new(Mutex::new(MonthlyUsage {
+                month: current_month(),
+                consumed: 0,
+            })),
+        }
+    }
+
+    // Waits no longer than the time left of the current request
+    pub async fn acquire(&self) -> Result<(), UpstreamError> {
+        if self.limits.per_second > 0 {
+            if self.queued.fetch_add(1, Ordering::SeqCst) >= self.limits.max_queue {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                return Err(RateLimitError::QueueFull {
+                    upstream: self.upstream.clone(),
+                }
+                .into());
+            }
+            let _slot = QueueSlot(&self.queued);
+            let remaining = remaining().filter(|r| *r < self.limits.queue_timeout);
+            let wait = remaining.unwrap_or(self.limits.queue_timeout);
+            if tokio::time::timeout(wait, self.take_token()).await.is_err() {
+                return Err(match remaining {
+                    Some(_) => UpstreamError::DeadlineExceeded,
+                    None => RateLimitError::QueueTimeout {
+                        upstream: self.upstream.clone(),
+                    }
+                    .into(),
+                });
+            }
+        }
+        self.record_usage();
+        Ok(())
+    }
+
+    async fn take_token(&self) {
+        let rate = self.limits.per_second as f64;
+        let mut bucket = self.bucket.lock().await;
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - bucket.tokens) / rate)).await;
+        }
+    }
+
+    // Counts the request in Redis so that the count survives restarts and is shared by
+    // all instances. The increment runs in the background, so the request doesn't wait
+    // on Redis. Each threshold is crossed by exactly one increment, so only one instance
+    // logs it.
+    fn record_usage(&self) {
+        let Some(quota) = self.limits.monthly_quota.filter(|q| *q > 0) else {
+            return;
+        };
+        let month = current_month();
+        let key = format!("{}/{}-{:02}", self.usage_key, month.0, month.1);
+        let label = self.label.clone();
+        let usage = self.usage.clone();
+        tokio::spawn(async move {
+            let consumed = match incr(&key, USAGE_TTL_SECS).await {
+                Ok(consumed) => consumed,
+                Err(_) => {
+                    let mut usage = usage.lock().unwrap();
+                    if usage.month != month {
+                        *usage = MonthlyUsage { month, consumed: 0 };
+                    }
+                    usage.consumed += 1;
+                    usage.consumed
+                }
+            };
+
+            if let Some(threshold) = crossed_threshold(consumed, quota) {
+                eprintln!(
+                    "{} has used {}% of its monthly quota ({} of {} requests)",
+                    label, threshold, consumed, quota
+                );
+            }
+        });
+    }
+}
+
+// usage counters outlive their month a bit, so the last days can still be looked at
+const USAGE_TTL_SECS: u64 = 40 * 24 * 60 * 60;
+
+// The alert threshold reached by the `consumed`-th request, if it is the first to reach it
+fn crossed_threshold(consumed: u64, quota: u64) -> Option<u64> {
+    let percent = |count: u64| count * 100 / quota;
+    QUOTA_ALERT_THRESHOLDS
+        .iter()
+        .rev()
+        .find(|t| percent(consumed) >= **t && percent(consumed.saturating_sub(1)) < **t)
+        .copied()
+}
+
+// FNV-1a, stable across instances and restarts unlike the std hasher
+fn hash_key(api_key: &str) -> u64 {
+    api_key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// (year, month) of the current UTC date
+fn current_month() -> (i64, u64) {
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (now_secs() / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u64)
+}
+
+// last characters of an API key, enough to tell keys apart in logs
+pub fn key_suffix(api_key: &str) -> &str {
+    api_key
+        .get(api_key.len().saturating_sub(4)..)
+        .unwrap_or_default()
+}
+
+static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<UpstreamLimiter>>>> = OnceLock::new();
+
+// Returns the shared limiter of the given upstream and API key
+pub fn limiter(upstream: &str, api_key: &str, limits: &UpstreamLimits) -> Arc<UpstreamLimiter> {
+    let mut limiters = LIMITERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    limiters
+        .entry(format!("{}/{}", upstream, api_key))
+        .or_insert_with(|| {
+            Arc::new(UpstreamLimiter::new(
+                upstream.to_string(),
+                format!("{} (key ...{})", upstream, key_suffix(api_key)),
+                format!("upstreamUsage/{}/{:016x}", upstream, hash_key(api_key)),
+                limits.clone(),
+            ))
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::deadline::with_deadline;
+
+    fn limiter(per_second: u32, max_queue: usize, queue_timeout_ms: u64) -> UpstreamLimiter {
+        UpstreamLimiter::new(
+            "Test".to_string(),
+            "Test".to_string(),
+            "upstreamUsage/Test/0".to_string(),
+            UpstreamLimits {
+                per_second,
+                max_queue,
+                queue_timeout: Duration::from_millis(queue_timeout_ms),
+                monthly_quota: None,
+            },
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_allows_a_burst_then_refills_at_the_rate() {
+        let limiter = limiter(2, 10, 60_000);
+        let start = Instant::now();
+        limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1));
+
+        limiter.acquire().await.unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(499), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_limit_never_waits() {
+        let limiter = limiter(0, 0, 0);
+        for _ in 0..100 {
+            limiter.acquire().await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_callers_beyond_the_queue_size() {
+        let limiter = Arc::new(limiter(1, 1, 60_000));
+        limiter.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await }
+        });
+        tokio::task::yield_now().await;
+
+        let rejected = limiter.acquire().await;
+        assert!(matches!(
+            rejected,
+            Err(UpstreamError::RateLimited(RateLimitError::QueueFull { .. }))
+        ));
+        assert!(waiting.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_waiting_for_a_token() {
+        let limiter = limiter(1, 10, 100);
+        limiter.acquire().await.unwrap();
+        let res = limiter.acquire().await;
+        assert!(matches!(
+            res,
+            Err(UpstreamError::RateLimited(
+                RateLimitError::QueueTimeout { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_no_longer_than_the_request_deadline() {
+        let limiter = limiter(1, 10, 60_000);
+        limiter.acquire().await.unwrap();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(200);
+        let res = with_deadline(deadline, limiter.acquire()).await;
+        assert!(matches!(res, Err(UpstreamError::DeadlineExceeded)));
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn alerts_once_per_threshold() {
+        let alerts: Vec<(u64, u64)> = (1..=12)
+            .filter_map(|consumed| crossed_threshold(consumed, 10).map(|t| (consumed, t)))
+            .collect();
+        assert_eq!(alerts, vec![(8, 80), (9, 90), (10, 100)]);
+    }
+}
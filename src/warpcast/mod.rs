@@ -0,0 +1,155 @@
+use std::fmt;
+
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::routes::config::Config;
+use crate::upstream::{
+    fixtures::{send, Fixtures},
+    UpstreamError,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WarpcastPfp {
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WarpcastUser {
+    pub fid: u64,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub pfp: Option<WarpcastPfp>,
+    #[serde(default)]
+    pub follower_count: u64,
+    #[serde(default)]
+    pub following_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct WarpcastUserResult {
+    user: WarpcastUser,
+}
+
+#[derive(Debug, Deserialize)]
+struct WarpcastUserResponse {
+    result: Option<WarpcastUserResult>,
+}
+
+#[derive(Debug)]
+pub enum WarpcastError {
+    InvalidHandle,
+    NotFound(String),
+    Upstream(UpstreamError),
+}
+
+impl fmt::Display for WarpcastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WarpcastError::InvalidHandle => write!(f, "Invalid handle"),
+            WarpcastError::NotFound(user) => write!(f, "User {} not found", user),
+            WarpcastError::Upstream(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for WarpcastError {}
+
+impl From<reqwest::Error> for WarpcastError {
+    fn from(e: reqwest::Error) -> Self {
+        WarpcastError::Upstream(UpstreamError::Http(e))
+    }
+}
+
+// Strips a leading `@`, surrounding whitespace and lowercases the handle
+pub fn normalize_handle(handle: &str) -> Option<String> {
+    let handle = handle.trim();
+    let handle = handle.strip_prefix('@').unwrap_or(handle).trim();
+    if handle.is_empty() {
+        None
+    } else {
+        Some(handle.to_lowercase())
+    }
+}
+
+pub struct WarpcastClient {
+    http: Client,
+    api_url: String,
+    fixtures: Fixtures,
+}
+
+impl WarpcastClient {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            http: Client::new(),
+            api_url: config.warpcast_api_url.clone(),
+            fixtures: config.fixtures.clone(),
+        }
+    }
+
+    pub async fn user_by_handle(&self, handle: &str) -> Result<WarpcastUser, WarpcastError> {
+        let username = normalize_handle(handle).ok_or(WarpcastError::InvalidHandle)?;
+        self.get_user(
+            "user-by-username",
+            &[("username", username.as_str())],
+            &username,
+        )
+        .await
+    }
+
+    pub async fn user_by_fid(&self, fid: u64) -> Result<WarpcastUser, WarpcastError> {
+        let fid = fid.to_string();
+        self.get_user("user", &[("fid", fid.as_str())], &fid).await
+    }
+
+    async fn get_user(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        identifier: &str,
+    ) -> Result<WarpcastUser, WarpcastError> {
+        let resp = send(
+            &self.fixtures,
+            "Warpcast",
+            self.http
+                .get(format!("{}/{}", self.api_url, path))
+                .query(query)
+                .header("accept", "application/json"),
+        )
+        .await
+        .map_err(WarpcastError::Upstream)?;
+
+        // unknown users come back as 404 with an `errors` body
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(WarpcastError::NotFound(identifier.to_string()));
+        }
+        if !resp.status().is_success() {
+            return Err(WarpcastError::Upstream(UpstreamError::Status {
+                upstream: "Warpcast".to_string(),
+                status: resp.status(),
+            }));
+        }
+
+        let user_resp: WarpcastUserResponse = resp.json().await?;
+        user_resp
+            .result
+            .map(|r| r.user)
+            .ok_or(WarpcastError::NotFound(identifier.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_handles() {
+        assert_eq!(normalize_handle("dwr"), Some("dwr".to_string()));
+        assert_eq!(normalize_handle("  @DWR.eth "), Some("dwr.eth".to_string()));
+        assert_eq!(normalize_handle("@ dwr"), Some("dwr".to_string()));
+        assert_eq!(normalize_handle("@1234"), Some("1234".to_string()));
+        assert_eq!(normalize_handle(""), None);
+        assert_eq!(normalize_handle(" @ "), None);
+    }
+}